@@ -0,0 +1,71 @@
+// Copyright (c) 2023-2024 Optimatist Technology Co., Ltd. All rights reserved.
+// DO NOT ALTER OR REMOVE COPYRIGHT NOTICES OR THIS FILE HEADER.
+//
+// This file is part of PSH.
+//
+// PSH is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License
+// as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+//
+// PSH is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even
+// the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License along with Performance Savior Home (PSH). If not,
+// see <https://www.gnu.org/licenses/>.
+
+use std::any::Any;
+
+use wasmtime::component::{Resource, ResourceTable, ResourceTableError};
+
+/// A `ResourceTable` which holds at most `max` entries, so a guest cannot
+/// pin an unbounded amount of host resources.
+#[derive(Debug)]
+pub struct BoundedTable {
+    table: ResourceTable,
+    len: usize,
+    max: usize,
+}
+
+impl Default for BoundedTable {
+    fn default() -> Self {
+        Self::new(usize::MAX)
+    }
+}
+
+impl BoundedTable {
+    pub fn new(max: usize) -> Self {
+        Self {
+            table: ResourceTable::new(),
+            len: 0,
+            max,
+        }
+    }
+
+    pub fn push<T>(&mut self, entry: T) -> Result<Resource<T>, ResourceTableError>
+    where
+        T: Send + 'static,
+    {
+        if self.len >= self.max {
+            return Err(ResourceTableError::Full);
+        }
+        let resource = self.table.push(entry)?;
+        self.len += 1;
+        Ok(resource)
+    }
+
+    pub fn get<T: Any + Sized>(&self, key: &Resource<T>) -> Result<&T, ResourceTableError> {
+        self.table.get(key)
+    }
+
+    pub fn get_mut<T: Any + Sized>(
+        &mut self,
+        key: &Resource<T>,
+    ) -> Result<&mut T, ResourceTableError> {
+        self.table.get_mut(key)
+    }
+
+    pub fn delete<T: Any>(&mut self, resource: Resource<T>) -> Result<T, ResourceTableError> {
+        let entry = self.table.delete(resource)?;
+        self.len -= 1;
+        Ok(entry)
+    }
+}
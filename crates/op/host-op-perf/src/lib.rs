@@ -12,11 +12,13 @@
 // You should have received a copy of the GNU Lesser General Public License along with Performance Savior Home (PSH). If not,
 // see <https://www.gnu.org/licenses/>.
 
-use wasmtime::component::{Linker, ResourceTable};
+use wasmtime::component::Linker;
 
 pub mod convert;
 pub mod counting;
 
+use host_op_common::BoundedTable;
+
 pub type Counter = perf_event_rs::counting::Counter;
 pub type CounterGroup = perf_event_rs::counting::CounterGroup;
 pub type FixedCounterGroup = perf_event_rs::counting::FixedCounterGroup;
@@ -40,14 +42,21 @@ wasmtime::component::bindgen!({
 });
 
 pub struct PerfCtx {
-    table: ResourceTable,
+    table: BoundedTable,
 }
 
 #[allow(clippy::new_without_default)]
 impl PerfCtx {
     pub fn new() -> Self {
         Self {
-            table: ResourceTable::new(),
+            table: BoundedTable::default(),
+        }
+    }
+
+    /// Limit the number of counters and groups a guest may hold at the same time.
+    pub fn with_max_resources(max: usize) -> Self {
+        Self {
+            table: BoundedTable::new(max),
         }
     }
 }
@@ -24,6 +24,7 @@ mod vmstat;
 
 use std::sync::Arc;
 
+use host_op_common::BoundedTable;
 use psh_system::{
     cpu::CpuHandle,
     disk::DiskHandle,
@@ -36,7 +37,7 @@ use psh_system::{
     vmstat::VmstatHandle,
     System,
 };
-use wasmtime::component::{Linker, ResourceTable};
+use wasmtime::component::Linker;
 
 pub type HostProc = Arc<Process>;
 
@@ -67,7 +68,7 @@ wasmtime::component::bindgen!({
 #[allow(dead_code)]
 #[derive(Debug, Default)]
 pub struct SysCtx {
-    table: ResourceTable,
+    table: BoundedTable,
     system: System,
     os: OsHandle,
     cpu: CpuHandle,
@@ -80,6 +81,16 @@ pub struct SysCtx {
     vmstat: VmstatHandle,
 }
 
+impl SysCtx {
+    /// Limit the number of processes a guest may hold at the same time.
+    pub fn with_max_resources(max: usize) -> Self {
+        Self {
+            table: BoundedTable::new(max),
+            ..Default::default()
+        }
+    }
+}
+
 pub fn add_to_linker<T>(
     l: &mut Linker<T>,
     f: impl (Fn(&mut T) -> &mut SysCtx) + Copy + Send + Sync + 'static,
@@ -19,7 +19,7 @@ use std::sync::Arc;
 use std::time::Duration;
 
 use psh_system::process::{ProcState, Process};
-use wasmtime::component::Resource;
+use wasmtime::component::{Resource, ResourceTableError};
 
 use crate::profiling::system::process::{
     self, ProcessStat as GuestProcessStat, ProcessState as GuestProcessState,
@@ -135,7 +135,7 @@ impl process::Host for SysCtx {
             Err(err) => return Ok(Err(err.to_string())),
         };
 
-        let processes = procs.into_iter().filter_map(|proc| {
+        let procs = procs.into_iter().filter_map(|proc| {
             let (Ok(stat), Ok(io), Ok(mem)) = (proc.stat(), proc.io(), proc.statm()) else {
                 return None;
             };
@@ -145,41 +145,53 @@ impl process::Host for SysCtx {
             Some((proc, stat, io, mem, state))
         });
 
-        let processes: Vec<_> = processes
-            .into_iter()
-            .map(|(proc, stat, io, mem, ref state)| {
-                let (pid, parent_id) = (proc.pid, stat.ppid);
-                match self.table.push(proc) {
-                    Ok(proc) => Ok(GuestProcessStat {
-                        pid,
-                        proc,
-                        name: stat.comm,
-                        utime: stat.utime * 1000 / self.system.tick_per_sec,
-                        stime: stat.stime * 1000 / self.system.tick_per_sec,
-                        cutime: stat.cutime * 1000 / self.system.tick_per_sec as i64,
-                        cstime: stat.cstime * 1000 / self.system.tick_per_sec as i64,
-                        priority: stat.priority,
-                        nice: stat.nice,
-                        num_threads: stat.num_threads,
-                        start_time: stat.starttime * 1000 / self.system.tick_per_sec,
-                        state: state.into(),
-                        written_bytes: io.write_bytes,
-                        read_bytes: io.read_bytes,
-                        memory_usage: mem.resident * self.system.page_size,
-                        virtual_memory_usage: mem.size * self.system.page_size,
-                        parent_id,
-                    }),
-                    Err(err) => Err(err),
+        let mut processes = Vec::new();
+        for (proc, stat, io, mem, ref state) in procs {
+            let (pid, parent_id) = (proc.pid, stat.ppid);
+            let proc = match self.table.push(proc) {
+                Ok(proc) => proc,
+                Err(err) => {
+                    // free the processes this call has pushed, a failed call must not pin them
+                    for process in processes {
+                        self.table.delete(process.proc)?;
+                    }
+                    return match err {
+                        err @ ResourceTableError::Full => Ok(Err(err.to_string())),
+                        err => Err(err.into()),
+                    };
                 }
-            })
-            .collect::<Result<_, _>>()?; // failure of this collect means self.table.push failed, so we give up
+            };
+            processes.push(GuestProcessStat {
+                pid,
+                proc,
+                name: stat.comm,
+                utime: stat.utime * 1000 / self.system.tick_per_sec,
+                stime: stat.stime * 1000 / self.system.tick_per_sec,
+                cutime: stat.cutime * 1000 / self.system.tick_per_sec as i64,
+                cstime: stat.cstime * 1000 / self.system.tick_per_sec as i64,
+                priority: stat.priority,
+                nice: stat.nice,
+                num_threads: stat.num_threads,
+                start_time: stat.starttime * 1000 / self.system.tick_per_sec,
+                state: state.into(),
+                written_bytes: io.write_bytes,
+                read_bytes: io.read_bytes,
+                memory_usage: mem.resident * self.system.page_size,
+                virtual_memory_usage: mem.size * self.system.page_size,
+                parent_id,
+            });
+        }
 
         Ok(Ok(processes))
     }
 
     fn current(&mut self) -> wasmtime::Result<Result<Resource<Arc<Process>>, String>> {
         let proc = match self.process.myself() {
-            Ok(proc) => Ok(self.table.push(proc)?),
+            Ok(proc) => match self.table.push(proc) {
+                Ok(proc) => Ok(proc),
+                Err(err @ ResourceTableError::Full) => Err(err.to_string()),
+                Err(err) => return Err(err.into()),
+            },
             Err(err) => Err(err.to_string()),
         };
         Ok(proc)
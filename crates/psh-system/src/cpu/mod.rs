@@ -58,18 +58,21 @@ impl FromStr for CpuMask {
 }
 
 #[derive(Debug, PartialEq, Eq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct TlbSize {
     pub count: u32,
     pub unit: u32,
 }
 
 #[derive(Debug, PartialEq, Eq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct AddressSizes {
     pub phy: u8,  // physical bits.
     pub virt: u8, // virtual bits.
 }
 
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct Arm64CpuInfo {
     pub processor: usize,
     pub bogomips: f32,
@@ -99,6 +102,7 @@ impl Arm64CpuInfo {
 }
 
 #[derive(Debug, PartialEq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct X86_64CpuInfo {
     pub processor: usize,
     pub vendor_id: String,
@@ -164,6 +168,7 @@ impl X86_64CpuInfo {
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub enum CpuInfo {
     X86_64(Vec<X86_64CpuInfo>),
     Arm64(Vec<Arm64CpuInfo>),
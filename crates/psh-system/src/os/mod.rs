@@ -20,6 +20,7 @@ use std::fmt::Display;
 pub use handle::OsHandle;
 
 #[derive(Debug, PartialEq, Eq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct KernelVersion {
     pub major: u8,
     pub minor: u8,
@@ -43,6 +44,7 @@ impl Display for KernelVersion {
 }
 
 #[derive(Debug, PartialEq, Eq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub enum DistroKind {
     Arch,
     CentOS,
@@ -82,12 +84,14 @@ impl Display for DistroKind {
 }
 
 #[derive(Debug, PartialEq, Eq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct DistroVersion {
     pub distro: DistroKind,
     pub version: Option<String>,
 }
 
 #[derive(Debug, PartialEq, Eq, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct OsInfo {
     pub distro: DistroVersion,
     pub kernel: KernelVersion,
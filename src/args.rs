@@ -12,13 +12,16 @@
 // You should have received a copy of the GNU Lesser General Public License along with Performance Savior Home (PSH). If not,
 // see <https://www.gnu.org/licenses/>.
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
 
 #[derive(Parser, Debug)]
 pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
     /// Config file
     /// └╴Will be generated if it does not exist
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     #[arg(value_name = "PATH")]
     #[arg(default_value = "/etc/psh/config.toml")]
     #[arg(verbatim_doc_comment)]
@@ -46,3 +49,122 @@ pub struct Args {
     #[arg(verbatim_doc_comment)]
     pub wasm_with_args: Option<Vec<String>>,
 }
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Run in the foreground, same as running without a subcommand
+    Run {
+        /// Use WASM from daemon config
+        #[arg(long)]
+        #[clap(visible_alias = "wdc")]
+        wasm_from_daemon_config: bool,
+
+        /// WASM binary followed with arguments
+        /// └╴e.g. /path/to/your.wasm foo bar baz
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+        #[arg(verbatim_doc_comment)]
+        wasm_with_args: Option<Vec<String>>,
+    },
+
+    /// Run as daemon, same as --daemon
+    Daemon,
+
+    /// Show the status of the running daemon
+    /// └╴Requires the control socket to be enabled
+    #[command(verbatim_doc_comment)]
+    Status,
+
+    /// Manage tasks of the running daemon
+    /// └╴Requires the control socket to be enabled
+    #[command(verbatim_doc_comment)]
+    Tasks {
+        #[command(subcommand)]
+        command: TasksCommand,
+    },
+
+    /// Check the config file
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+
+    /// Print host info as JSON
+    Info,
+
+    /// List imports and exports of a WASM component
+    /// └╴And check them against the host interfaces psh provides
+    #[command(verbatim_doc_comment)]
+    Inspect {
+        /// WASM binary
+        wasm: String,
+    },
+
+    /// Generate an ed25519 key pair to sign WASM components
+    /// └╴The public key is printed for `runtime.signature.trusted_keys`
+    #[command(verbatim_doc_comment)]
+    Keygen {
+        /// Write the private key to this path
+        #[arg(short, long)]
+        #[arg(value_name = "PATH")]
+        output: String,
+    },
+
+    /// Sign a WASM component
+    /// └╴Writes a detached signature to <WASM>.sig unless --embed is given
+    #[command(verbatim_doc_comment)]
+    Sign {
+        /// WASM binary
+        wasm: String,
+
+        /// Private key generated by `psh keygen`
+        #[arg(short, long)]
+        #[arg(value_name = "PATH")]
+        key: String,
+
+        /// Embed the signature in the component instead
+        #[arg(long)]
+        embed: bool,
+
+        /// Write the signature, or the signed component with --embed, to this path
+        #[arg(short, long)]
+        #[arg(value_name = "PATH")]
+        output: Option<String>,
+    },
+
+    /// Compile a WASM component ahead of time
+    /// └╴Stored in the component cache unless --output is given
+    #[command(verbatim_doc_comment)]
+    Compile {
+        /// WASM binary
+        wasm: String,
+
+        /// Write the precompiled component to this path instead
+        #[arg(short, long)]
+        #[arg(value_name = "PATH")]
+        output: Option<String>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum TasksCommand {
+    /// List queued, running and recently finished tasks
+    List,
+
+    /// Cancel a queued or running task
+    Cancel {
+        /// Task key shown by `psh tasks list`
+        key: String,
+    },
+
+    /// Print stdout and stderr of a finished task
+    Logs {
+        /// Task key shown by `psh tasks list`
+        key: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ConfigCommand {
+    /// Parse and validate the config file without generating it
+    Check,
+}
@@ -0,0 +1,188 @@
+// Copyright (c) 2023-2024 Optimatist Technology Co., Ltd. All rights reserved.
+// DO NOT ALTER OR REMOVE COPYRIGHT NOTICES OR THIS FILE HEADER.
+//
+// This file is part of PSH.
+//
+// PSH is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License
+// as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+//
+// PSH is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even
+// the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License along with Performance Savior Home (PSH). If not,
+// see <https://www.gnu.org/licenses/>.
+
+//! Subcommands that do not run tasks themselves.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+
+use anyhow::{anyhow, bail, Result};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use ed25519_dalek::SigningKey;
+use psh_system::cpu::CpuHandle;
+use psh_system::os::OsHandle;
+use serde_json::json;
+
+use crate::args::{ConfigCommand, TasksCommand};
+use crate::config::{self, Config, RuntimeConfig};
+use crate::runtime::signature::{self, Verifier};
+use crate::runtime::{ComponentCache, DataExportCtx, PshEngineBuilder};
+use crate::services::control;
+
+/// Compile a component ahead of time, into the component cache or to `output`.
+pub fn compile(cfg: &RuntimeConfig, wasm: &str, output: Option<&str>) -> Result<()> {
+    let engine = PshEngineBuilder::new().limits(cfg.limits.clone()).build()?;
+    let binary = fs::read(wasm)?;
+
+    match output {
+        Some(output) => fs::write(output, engine.engine.precompile_component(&binary)?)?,
+        None => {
+            if !cfg.cache.enable {
+                bail!("Component cache is disabled, please specify the output path");
+            }
+            ComponentCache::new(&cfg.cache.dir, cfg.cache.max_size)
+                .load(&engine.engine, &binary)?;
+        }
+    }
+
+    Ok(())
+}
+
+pub fn inspect(cfg: &RuntimeConfig, wasm: &str) -> Result<()> {
+    let engine = PshEngineBuilder::new()
+        .allow_perf_op(true)
+        .allow_system_op(true)
+        .allow_data_export_op(Some(DataExportCtx { ctx: None }))
+        .allow_precompiled(true)
+        .limits(cfg.limits.clone())
+        .build()?;
+    let component = engine.load(&fs::read(wasm)?)?;
+    let inspection = engine.inspect(&component);
+
+    println!("imports:");
+    for (name, kind) in &inspection.imports {
+        println!("  {name} ({kind})");
+    }
+    println!("exports:");
+    for (name, kind) in &inspection.exports {
+        println!("  {name} ({kind})");
+    }
+    if !inspection.problems.is_empty() {
+        println!("problems:");
+        for problem in &inspection.problems {
+            println!("  {problem}");
+        }
+        bail!("Component is incompatible with this psh");
+    }
+    Ok(())
+}
+
+pub fn keygen(output: &str) -> Result<()> {
+    let mut seed = [0; 32];
+    File::open("/dev/urandom")?.read_exact(&mut seed)?;
+    let key = SigningKey::from_bytes(&seed);
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(output)?;
+    writeln!(file, "{}", BASE64_STANDARD.encode(seed))?;
+    println!("{}", BASE64_STANDARD.encode(key.verifying_key()));
+    Ok(())
+}
+
+pub fn sign(wasm: &str, key: &str, embed: bool, output: Option<&str>) -> Result<()> {
+    let seed = BASE64_STANDARD.decode(fs::read_to_string(key)?.trim())?;
+    let seed = seed
+        .try_into()
+        .map_err(|_| anyhow!("Invalid private key"))?;
+    let key = SigningKey::from_bytes(&seed);
+
+    let signed = signature::sign(&key, &fs::read(wasm)?, embed);
+    let output = match (output, embed) {
+        (Some(output), _) => output.to_owned(),
+        (None, true) => wasm.to_owned(),
+        (None, false) => format!("{wasm}.sig"),
+    };
+    fs::write(output, signed)?;
+    Ok(())
+}
+
+pub fn config(path: &str, command: &ConfigCommand) -> Result<()> {
+    match command {
+        ConfigCommand::Check => {
+            let cfg = config::read(path)?;
+            cfg.check()?;
+            Verifier::new(&cfg.runtime.signature)?;
+            println!("{path}: ok");
+        }
+    }
+    Ok(())
+}
+
+pub fn info() -> Result<()> {
+    let info = json!({
+        "os": OsHandle::new().info()?,
+        "cpu": CpuHandle::new().info()?,
+    });
+    println!("{}", serde_json::to_string_pretty(&info)?);
+    Ok(())
+}
+
+pub fn status(cfg: &Config) -> Result<()> {
+    let resp = control::call(&cfg.control.path, &json!({ "cmd": "status" }))?;
+    println!("pid:     {}", resp["pid"]);
+    println!("version: {}", resp["version"].as_str().unwrap_or_default());
+    println!("queued:  {}", resp["queued"]);
+    println!("running: {}", resp["running"]);
+    if let Some(spool) = resp["spool"].as_object() {
+        println!(
+            "spool:   {} bytes pending, {} spooled, {} replayed, {} dropped",
+            spool["pending"], spool["spooled"], spool["replayed"], spool["dropped"]
+        );
+    }
+    if let Some(export) = resp["export"].as_object() {
+        println!(
+            "export:  {} bytes, {} sent, ratio {:.2}",
+            export["raw_bytes"],
+            export["sent_bytes"],
+            export["ratio"].as_f64().unwrap_or(1.0)
+        );
+    }
+    Ok(())
+}
+
+pub fn tasks(cfg: &Config, command: &TasksCommand) -> Result<()> {
+    let path = &cfg.control.path;
+    match command {
+        TasksCommand::List => {
+            let resp = control::call(path, &json!({ "cmd": "list" }))?;
+            let tasks = resp["tasks"].as_array().cloned().unwrap_or_default();
+            println!("{:<40} {:<14} {:<5} ERROR", "KEY", "STATE", "EXIT");
+            for it in tasks {
+                println!(
+                    "{:<40} {:<14} {:<5} {}",
+                    it["key"].as_str().unwrap_or_default(),
+                    it["state"].as_str().unwrap_or_default(),
+                    it["exit_code"]
+                        .as_i64()
+                        .map(|it| it.to_string())
+                        .unwrap_or_default(),
+                    it["error"].as_str().unwrap_or_default(),
+                );
+            }
+        }
+        TasksCommand::Cancel { key } => {
+            control::call(path, &json!({ "cmd": "cancel", "key": key }))?;
+        }
+        TasksCommand::Logs { key } => {
+            let resp = control::call(path, &json!({ "cmd": "output", "key": key }))?;
+            std::io::stdout().write_all(resp["stdout"].as_str().unwrap_or_default().as_bytes())?;
+            std::io::stderr().write_all(resp["stderr"].as_str().unwrap_or_default().as_bytes())?;
+        }
+    }
+    Ok(())
+}
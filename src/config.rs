@@ -12,9 +12,10 @@
 // You should have received a copy of the GNU Lesser General Public License along with Performance Savior Home (PSH). If not,
 // see <https://www.gnu.org/licenses/>.
 
-use std::{fs, path::Path};
+use std::{collections::BTreeMap, fs, net::SocketAddr, path::Path};
 
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
+use regex::RegexSet;
 use serde::Deserialize;
 
 const TEMPLATE: &str = include_str!("../doc/config.toml");
@@ -22,6 +23,10 @@ const TEMPLATE: &str = include_str!("../doc/config.toml");
 #[derive(Deserialize)]
 pub struct Config {
     pub daemon: DaemonConfig,
+    #[serde(default)]
+    pub runtime: RuntimeConfig,
+    #[serde(default)]
+    pub control: ControlConfig,
     pub remote: RemoteConfig,
 }
 
@@ -41,11 +46,244 @@ pub struct DaemonWasmConfig {
     pub args: Vec<String>,
 }
 
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct RuntimeConfig {
+    /// max number of tasks running at the same time
+    pub max_concurrency: usize,
+    /// in seconds, how long to wait for tasks to stop and flush their data on shutdown
+    pub shutdown_grace_period: u64,
+    pub limits: LimitsConfig,
+    pub cache: CacheConfig,
+    pub fs: FsConfig,
+    pub env: EnvConfig,
+    pub signature: SignatureConfig,
+    pub file_sink: FileSinkConfig,
+}
+
+impl Default for RuntimeConfig {
+    fn default() -> Self {
+        Self {
+            max_concurrency: 4,
+            shutdown_grace_period: 10,
+            limits: LimitsConfig::default(),
+            cache: CacheConfig::default(),
+            fs: FsConfig::default(),
+            env: EnvConfig::default(),
+            signature: SignatureConfig::default(),
+            file_sink: FileSinkConfig::default(),
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+pub struct CacheConfig {
+    pub enable: bool,
+    pub dir: String,
+    /// in bytes
+    pub max_size: u64,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            enable: true,
+            dir: "/var/cache/psh".to_owned(),
+            max_size: 256 << 20,
+        }
+    }
+}
+
+/// Local files receiving the data exported by guests when `remote.rpc` is disabled.
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct FileSinkConfig {
+    pub enable: bool,
+    pub dir: String,
+    /// in bytes, of each line protocol file
+    pub max_file_size: u64,
+    /// number of line protocol files kept
+    pub max_files: usize,
+}
+
+impl Default for FileSinkConfig {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            dir: "/var/lib/psh/data".to_owned(),
+            max_file_size: 64 << 20,
+            max_files: 4,
+        }
+    }
+}
+
+/// Ed25519 signatures of components, checked before they are compiled.
+#[derive(Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct SignatureConfig {
+    pub policy: SignaturePolicy,
+    /// base64 encoded public keys
+    pub trusted_keys: Vec<String>,
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SignaturePolicy {
+    /// run components without checking signatures
+    #[default]
+    Off,
+    /// log components not signed by a trusted key, but run them
+    Warn,
+    /// reject components not signed by a trusted key
+    Require,
+}
+
+/// Host environment variables visible to guests, nothing is visible by default.
+///
+/// Patterns match a whole name, or a prefix when ending with `*`.
+#[derive(Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct EnvConfig {
+    pub allow: Vec<String>,
+    /// takes precedence over `allow`
+    pub deny: Vec<String>,
+}
+
+impl EnvConfig {
+    pub fn allows(&self, name: &str) -> bool {
+        let matches = |pattern: &String| name_matches(pattern, name);
+        self.allow.iter().any(matches) && !self.deny.iter().any(matches)
+    }
+}
+
+/// Whether `name` matches `pattern`, a whole name or a prefix when ending with `*`.
+pub fn name_matches(pattern: &str, name: &str) -> bool {
+    pattern
+        .strip_suffix('*')
+        .map_or_else(|| name == pattern, |prefix| name.starts_with(prefix))
+}
+
+/// Host directories visible to guests, nothing is visible by default.
+#[derive(Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct FsConfig {
+    pub preopens: Vec<PreopenConfig>,
+    pub scratch: ScratchConfig,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct PreopenConfig {
+    pub host: String,
+    /// defaults to the host path
+    pub guest: Option<String>,
+    #[serde(default = "PreopenConfig::default_dir_perms")]
+    pub dir_perms: Vec<DirPerm>,
+    #[serde(default = "PreopenConfig::default_file_perms")]
+    pub file_perms: Vec<FilePerm>,
+}
+
+impl PreopenConfig {
+    fn default_dir_perms() -> Vec<DirPerm> {
+        vec![DirPerm::Read]
+    }
+
+    fn default_file_perms() -> Vec<FilePerm> {
+        vec![FilePerm::Read]
+    }
+}
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DirPerm {
+    Read,
+    Mutate,
+}
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FilePerm {
+    Read,
+    Write,
+}
+
+/// A writable directory created for each task and removed after it finishes.
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct ScratchConfig {
+    pub enable: bool,
+    /// parent of the per-task directories on the host
+    pub dir: String,
+    /// where the guest sees its scratch directory
+    pub guest: String,
+}
+
+impl Default for ScratchConfig {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            dir: "/var/lib/psh/scratch".to_owned(),
+            guest: "/scratch".to_owned(),
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct LimitsConfig {
+    /// in bytes, for each linear memory
+    pub max_memory: usize,
+    pub max_instances: usize,
+    pub max_tables: usize,
+    pub max_table_elements: usize,
+    /// fuel a task can consume, 0 disables fuel metering
+    pub fuel: u64,
+    /// resources a guest can hold in each host operator, e.g. perf counters or processes,
+    /// a call that would hold more returns an error to the guest
+    pub max_host_resources: usize,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_memory: 512 << 20,
+            max_instances: 1000,
+            max_tables: 1000,
+            max_table_elements: 1_000_000,
+            fuel: 0,
+            max_host_resources: 4096,
+        }
+    }
+}
+
+/// Unix socket to submit and inspect tasks locally, only accessible by root.
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct ControlConfig {
+    pub enable: bool,
+    pub path: String,
+    /// in bytes, stdout and stderr captured from each submitted task
+    pub max_output: usize,
+}
+
+impl Default for ControlConfig {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            path: "/run/psh/control.sock".to_owned(),
+            max_output: 1 << 20,
+        }
+    }
+}
+
 #[derive(Deserialize)]
 pub struct RemoteConfig {
     pub token: String,
     pub rpc: RpcConfig,
+    #[serde(default)]
+    pub influxdb: InfluxdbConfig,
     pub otlp: OtlpConfig,
+    #[serde(default)]
+    pub prometheus: PrometheusConfig,
 }
 
 #[derive(Deserialize)]
@@ -55,21 +293,393 @@ pub struct RpcConfig {
     /// in seconds
     pub heartbeat_interval: u64,
     pub instance_id_file: String,
+    #[serde(default)]
+    pub compression: Compression,
     pub data_export: DataExportConfig,
 }
 
+/// Compression of rpc requests, only used once the server accepts it.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Compression {
+    None,
+    #[default]
+    Gzip,
+    Zstd,
+}
+
+/// InfluxDB receiving the data exported by guests instead of the rpc service.
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct InfluxdbConfig {
+    pub enable: bool,
+    pub url: String,
+    pub org: String,
+    pub bucket: String,
+    pub token: String,
+    pub precision: Precision,
+    /// in seconds
+    pub timeout: u64,
+    pub gzip: bool,
+}
+
+impl Default for InfluxdbConfig {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            url: "http://localhost:8086".to_owned(),
+            org: String::new(),
+            bucket: "psh".to_owned(),
+            token: String::new(),
+            precision: Precision::Ns,
+            timeout: 10,
+            gzip: true,
+        }
+    }
+}
+
+/// Precision of line protocol timestamps.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Precision {
+    #[default]
+    Ns,
+    Us,
+    Ms,
+    S,
+}
+
+impl Precision {
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Ns => "ns",
+            Self::Us => "us",
+            Self::Ms => "ms",
+            Self::S => "s",
+        }
+    }
+
+    /// Nanoseconds in one unit.
+    pub const fn nanos(self) -> u64 {
+        match self {
+            Self::Ns => 1,
+            Self::Us => 1_000,
+            Self::Ms => 1_000_000,
+            Self::S => 1_000_000_000,
+        }
+    }
+}
+
 #[derive(Deserialize)]
 pub struct OtlpConfig {
     pub enable: bool,
     pub addr: String,
     /// in seconds
     pub interval: u64,
+    /// rates computed from the system counters, see `doc/metrics.md`
+    #[serde(default)]
+    pub derived_metrics: bool,
+    /// record the metrics and measurements exported by guests, also exposed to prometheus
+    #[serde(default)]
+    pub guest_metrics: bool,
+    /// guest metrics holding increments, `*` suffix matches a prefix
+    #[serde(default)]
+    pub guest_counters: Vec<String>,
+    /// distinct guest metrics each task can record
+    #[serde(default = "OtlpConfig::default_guest_max_metrics")]
+    pub guest_max_metrics: usize,
+    /// distinct guest metrics all tasks can record over the life of the daemon
+    #[serde(default = "OtlpConfig::default_guest_max_total_metrics")]
+    pub guest_max_total_metrics: usize,
+    /// resource attributes added to the detected host identity
+    #[serde(default)]
+    pub resource: BTreeMap<String, String>,
+    #[serde(default)]
+    pub groups: MetricGroupsConfig,
+    #[serde(default)]
+    pub protocol: OtlpProtocol,
+    /// in seconds, of each export request
+    #[serde(default = "OtlpConfig::default_timeout")]
+    pub timeout: u64,
+    /// zstd is only supported over grpc
+    #[serde(default = "OtlpConfig::default_compression")]
+    pub compression: Compression,
+    /// sent with each export request, as grpc metadata over grpc
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    #[serde(default)]
+    pub tls: OtlpTlsConfig,
+    /// proxy url of http/protobuf, `HTTPS_PROXY` and `NO_PROXY` are used when not set
+    #[serde(default)]
+    pub proxy: Option<String>,
+}
+
+impl OtlpConfig {
+    const fn default_guest_max_metrics() -> usize {
+        256
+    }
+
+    const fn default_guest_max_total_metrics() -> usize {
+        4096
+    }
+
+    const fn default_timeout() -> u64 {
+        10
+    }
+
+    const fn default_compression() -> Compression {
+        Compression::None
+    }
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub enum OtlpProtocol {
+    #[default]
+    #[serde(rename = "grpc")]
+    Grpc,
+    /// `/v1/metrics` is appended to the addr
+    #[serde(rename = "http/protobuf")]
+    HttpProtobuf,
+}
+
+/// PEM files replacing the system roots and authenticating psh to the collector.
+#[derive(Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct OtlpTlsConfig {
+    /// plaintext, for local collectors with an `http://` addr
+    pub insecure: bool,
+    pub ca_file: Option<String>,
+    pub cert_file: Option<String>,
+    pub key_file: Option<String>,
+}
+
+/// System metrics by the source they are read from.
+#[derive(Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct MetricGroupsConfig {
+    pub cpu: MetricGroupConfig,
+    pub memory: MetricGroupConfig,
+    pub disk: MetricGroupConfig,
+    pub network: MetricGroupConfig,
+    pub interrupt: MetricGroupConfig,
+    pub rps: MetricGroupConfig,
+    pub vmstat: MetricGroupConfig,
+    pub process: ProcessGroupConfig,
+}
+
+impl MetricGroupsConfig {
+    pub fn iter(&self) -> impl Iterator<Item = (&'static str, &MetricGroupConfig)> {
+        [
+            ("cpu", &self.cpu),
+            ("memory", &self.memory),
+            ("disk", &self.disk),
+            ("network", &self.network),
+            ("interrupt", &self.interrupt),
+            ("rps", &self.rps),
+            ("vmstat", &self.vmstat),
+        ]
+        .into_iter()
+    }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct MetricGroupConfig {
+    pub enable: bool,
+    /// in seconds, defaults to `remote.otlp.interval`
+    pub interval: Option<u64>,
+    /// regexes of the device names, all devices if empty
+    pub include: Vec<String>,
+    /// takes precedence over `include`
+    pub exclude: Vec<String>,
+    /// names of the memory fields or vmstat keys, all if empty
+    pub fields: Vec<String>,
+}
+
+impl Default for MetricGroupConfig {
+    fn default() -> Self {
+        Self {
+            enable: true,
+            interval: None,
+            include: vec![],
+            exclude: vec![],
+            fields: vec![],
+        }
+    }
+}
+
+impl MetricGroupConfig {
+    pub fn devices(&self) -> Result<DeviceFilter> {
+        Ok(DeviceFilter {
+            include: RegexSet::new(&self.include)?,
+            exclude: RegexSet::new(&self.exclude)?,
+        })
+    }
+}
+
+/// Processes selected by name, command line and user, optionally only the top ones.
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct ProcessGroupConfig {
+    pub enable: bool,
+    /// in seconds, defaults to `remote.otlp.interval`
+    pub interval: Option<u64>,
+    /// regexes of the process names, all processes if empty
+    pub include: Vec<String>,
+    /// takes precedence over `include`
+    pub exclude: Vec<String>,
+    /// regexes of the command lines, arguments joined by spaces, any if empty
+    pub cmdline: Vec<String>,
+    /// any user if empty
+    pub uids: Vec<u32>,
+    /// only the processes ranked first by `top_by`, all if 0
+    pub top: usize,
+    pub top_by: ProcessOrder,
+    /// a series for each process labeled by its pid, otherwise processes are aggregated by name
+    pub pid: bool,
+}
+
+impl Default for ProcessGroupConfig {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            interval: None,
+            include: vec![],
+            exclude: vec![],
+            cmdline: vec![],
+            uids: vec![],
+            top: 10,
+            top_by: ProcessOrder::Cpu,
+            pid: false,
+        }
+    }
+}
+
+impl ProcessGroupConfig {
+    /// The settings shared with the other groups, process names are the devices.
+    pub fn group(&self) -> MetricGroupConfig {
+        MetricGroupConfig {
+            enable: self.enable,
+            interval: self.interval,
+            include: self.include.clone(),
+            exclude: self.exclude.clone(),
+            fields: vec![],
+        }
+    }
+
+    pub fn cmdlines(&self) -> Result<RegexSet> {
+        Ok(RegexSet::new(&self.cmdline)?)
+    }
 }
 
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ProcessOrder {
+    /// CPU time since the last reading
+    #[default]
+    Cpu,
+    /// resident memory
+    Memory,
+}
+
+#[derive(Clone)]
+pub struct DeviceFilter {
+    include: RegexSet,
+    exclude: RegexSet,
+}
+
+impl DeviceFilter {
+    pub fn allows(&self, name: &str) -> bool {
+        (self.include.is_empty() || self.include.is_match(name)) && !self.exclude.is_match(name)
+    }
+}
+
+/// HTTP endpoint serving the metrics at `/metrics` in the Prometheus text format.
 #[derive(Deserialize)]
+#[serde(default)]
+pub struct PrometheusConfig {
+    pub enable: bool,
+    pub listen: SocketAddr,
+}
+
+impl Default for PrometheusConfig {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            listen: SocketAddr::from(([0, 0, 0, 0], 9464)),
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
 pub struct DataExportConfig {
+    /// in bytes, data queued by each task
     pub buf_size: usize,
     pub buf_watermark: usize,
+    #[serde(default)]
+    pub overflow: OverflowPolicy,
+    /// in seconds, 0 only sends by `buf_watermark`
+    #[serde(default = "DataExportConfig::default_max_batch_age")]
+    pub max_batch_age: u64,
+    /// in bytes, of each request
+    #[serde(default = "DataExportConfig::default_max_batch_size")]
+    pub max_batch_size: usize,
+    #[serde(default)]
+    pub spool: SpoolConfig,
+}
+
+impl DataExportConfig {
+    const fn default_max_batch_age() -> u64 {
+        10
+    }
+
+    const fn default_max_batch_size() -> usize {
+        1 << 20
+    }
+}
+
+/// What to do with data exported by a task when its buffer is full.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OverflowPolicy {
+    /// wait until the buffered data is sent
+    Block,
+    /// discard the new data
+    DropNewest,
+    /// discard the oldest buffered data to make room, so guests are never throttled
+    #[default]
+    DropOldest,
+    /// discard the new data and return an error to the guest
+    Error,
+}
+
+/// Data the rpc backend could not take, kept on disk and replayed later.
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct SpoolConfig {
+    pub enable: bool,
+    pub dir: String,
+    /// in bytes, the oldest data is dropped beyond this
+    pub max_size: u64,
+    /// in bytes
+    pub segment_size: u64,
+    /// in seconds
+    pub backoff_min: u64,
+    /// in seconds
+    pub backoff_max: u64,
+}
+
+impl Default for SpoolConfig {
+    fn default() -> Self {
+        Self {
+            enable: true,
+            dir: "/var/lib/psh/spool".to_owned(),
+            max_size: 64 << 20,
+            segment_size: 4 << 20,
+            backoff_min: 1,
+            backoff_max: 300,
+        }
+    }
 }
 
 pub fn read_or_gen<P>(path: P) -> Result<Config>
@@ -80,12 +690,104 @@ where
     if !path.exists() {
         fs::write(path, TEMPLATE)?;
     }
+    read(path)
+}
+
+pub fn read<P>(path: P) -> Result<Config>
+where
+    P: AsRef<Path>,
+{
     let cfg = fs::read_to_string(path)?;
     let cfg: Config = toml::from_str(&cfg)?;
     Ok(cfg)
 }
 
+impl Config {
+    /// Catch mistakes the parser can't, e.g. options that contradict each other.
+    pub fn check(&self) -> Result<()> {
+        if self.daemon.wasm.enable && self.daemon.wasm.path.is_empty() {
+            bail!("daemon.wasm.path is required when daemon.wasm is enabled");
+        }
+        if self.runtime.max_concurrency == 0 {
+            bail!("runtime.max_concurrency must be greater than 0");
+        }
+        let file_sink = &self.runtime.file_sink;
+        if file_sink.max_file_size == 0 || file_sink.max_files == 0 {
+            bail!("runtime.file_sink.max_file_size and max_files must be greater than 0");
+        }
+        let influxdb = &self.remote.influxdb;
+        if influxdb.enable && influxdb.bucket.is_empty() {
+            bail!("remote.influxdb.bucket is required when remote.influxdb is enabled");
+        }
+        let data_export = &self.remote.rpc.data_export;
+        if data_export.buf_size == 0 {
+            bail!("remote.rpc.data_export.buf_size must be greater than 0");
+        }
+        if data_export.buf_watermark > data_export.buf_size {
+            bail!("remote.rpc.data_export.buf_watermark must not exceed buf_size");
+        }
+        if data_export.max_batch_size == 0 {
+            bail!("remote.rpc.data_export.max_batch_size must be greater than 0");
+        }
+        let spool = &data_export.spool;
+        if spool.segment_size == 0 || spool.segment_size > spool.max_size {
+            bail!("remote.rpc.data_export.spool.segment_size must be in 1..=max_size");
+        }
+        if spool.backoff_min == 0 || spool.backoff_min > spool.backoff_max {
+            bail!("remote.rpc.data_export.spool.backoff_min must be in 1..=backoff_max");
+        }
+        let otlp = &self.remote.otlp;
+        if otlp.enable {
+            if otlp.tls.insecure && !otlp.addr.starts_with("http://") {
+                bail!("remote.otlp.addr must be http:// when remote.otlp.tls.insecure is set");
+            }
+            if otlp.tls.cert_file.is_some() != otlp.tls.key_file.is_some() {
+                bail!("remote.otlp.tls.cert_file and key_file must be set together");
+            }
+            if otlp.protocol == OtlpProtocol::HttpProtobuf && otlp.compression == Compression::Zstd
+            {
+                bail!("remote.otlp.compression zstd is only supported over grpc");
+            }
+            if otlp.protocol == OtlpProtocol::Grpc && otlp.proxy.is_some() {
+                bail!("remote.otlp.proxy is only supported over http/protobuf");
+            }
+        }
+        // groups are read when the metrics are collected, so at most once per reader interval
+        let too_short = |it: u64| it == 0 || it < otlp.interval;
+        for (name, group) in otlp.groups.iter() {
+            if group.interval.is_some_and(too_short) {
+                bail!("remote.otlp.groups.{name}.interval must be greater than 0 and at least remote.otlp.interval");
+            }
+            group
+                .devices()
+                .with_context(|| format!("remote.otlp.groups.{name}: invalid regex"))?;
+        }
+        let process = &otlp.groups.process;
+        if process.interval.is_some_and(too_short) {
+            bail!("remote.otlp.groups.process.interval must be greater than 0 and at least remote.otlp.interval");
+        }
+        process
+            .group()
+            .devices()
+            .and_then(|_| process.cmdlines())
+            .context("remote.otlp.groups.process: invalid regex")?;
+        for it in &self.runtime.fs.preopens {
+            if !Path::new(&it.host).is_dir() {
+                bail!("runtime.fs.preopens: {} is not a directory", it.host);
+            }
+        }
+        Ok(())
+    }
+}
+
 #[test]
 fn parse_config_template() {
     toml::from_str::<Config>(TEMPLATE).unwrap();
 }
+
+#[test]
+fn parse_partial_runtime() {
+    let runtime: RuntimeConfig = toml::from_str("[env]\nallow = [\"LANG\"]").unwrap();
+    assert_eq!(runtime.max_concurrency, 4);
+    assert_eq!(runtime.env.allow, ["LANG"]);
+}
@@ -13,28 +13,36 @@
 // see <https://www.gnu.org/licenses/>.
 
 mod args;
+mod commands;
 mod config;
 mod daemon;
 mod log;
 mod otlp;
 mod runtime;
 mod services;
+#[cfg(test)]
+mod test_utils;
 
+use std::sync::Arc;
 use std::time::Duration;
 use std::{fs, thread};
 
-use anyhow::{bail, Error, Result};
-use args::Args;
+use anyhow::{bail, Context, Error, Result};
+use args::{Args, Command};
 use chrono::{TimeZone, Utc};
 use clap::Parser;
-use config::RemoteConfig;
+use config::{Config, ControlConfig, RemoteConfig};
 use daemon::{get_daemon_wasm_args, spawn_daemon};
 use log::log_init;
 use nix::unistd::geteuid;
-use opentelemetry_otlp::ExportConfig;
 use psh_proto::HeartbeatReq;
-use runtime::{Task, TaskRuntime};
-use services::rpc::RpcClient;
+use runtime::{signature, Sink, Task, TaskReport, TaskRuntime};
+use services::influxdb::InfluxClient;
+use services::rpc::{ExportCounters, RpcClient};
+use services::spool::Spool;
+use services::{control, prometheus};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{oneshot, watch};
 use tokio::try_join;
 
 use mimalloc::MiMalloc;
@@ -45,35 +53,59 @@ static GLOBAL: MiMalloc = mimalloc::MiMalloc;
 fn main() -> Result<()> {
     log_init();
 
+    let args = Args::parse();
+    let cfg = || config::read_or_gen(&args.config);
+    match args.command {
+        Some(Command::Config { command }) => commands::config(&args.config, &command),
+        Some(Command::Info) => commands::info(),
+        Some(Command::Keygen { output }) => commands::keygen(&output),
+        Some(Command::Sign {
+            wasm,
+            key,
+            embed,
+            output,
+        }) => commands::sign(&wasm, &key, embed, output.as_deref()),
+        Some(Command::Inspect { wasm }) => commands::inspect(&cfg()?.runtime, &wasm),
+        Some(Command::Compile { wasm, output }) => {
+            commands::compile(&cfg()?.runtime, &wasm, output.as_deref())
+        }
+        Some(Command::Status) => commands::status(&cfg()?),
+        Some(Command::Tasks { command }) => commands::tasks(&cfg()?, &command),
+        Some(Command::Run {
+            wasm_from_daemon_config,
+            wasm_with_args,
+        }) => run(cfg()?, false, wasm_from_daemon_config, wasm_with_args),
+        Some(Command::Daemon) => run(cfg()?, true, false, None),
+        None => run(
+            cfg()?,
+            args.daemon,
+            args.wasm_from_daemon_config,
+            args.wasm_with_args,
+        ),
+    }
+}
+
+fn run(
+    cfg: Config,
+    daemon: bool,
+    wasm_from_daemon_config: bool,
+    wasm_with_args: Option<Vec<String>>,
+) -> Result<()> {
     if !geteuid().is_root() {
         bail!("Insufficient privileges. Please run psh with root permissions.");
     }
+    cfg.check()?;
 
-    let args = Args::parse();
-    let cfg = config::read_or_gen(args.config.clone())?;
-
-    let wasm_with_args = match args {
-        Args {
-            daemon: true,
-            wasm_with_args: None,
-            ..
-        } => {
+    let pid_file = daemon.then(|| cfg.daemon.pid_file.clone());
+    let wasm_with_args = match (daemon, wasm_with_args) {
+        (true, None) => {
             spawn_daemon(cfg.daemon.clone())?;
             get_daemon_wasm_args(cfg.daemon.wasm.clone())
         }
-        Args {
-            daemon: true,
-            wasm_with_args: Some(_),
-            ..
-        } => {
+        (true, Some(_)) => {
             bail!("Invalid argument, WASM can only be configured in the config file in daemon mode")
         }
-        Args {
-            daemon: false,
-            wasm_from_daemon_config,
-            wasm_with_args,
-            ..
-        } => {
+        (false, wasm_with_args) => {
             if wasm_from_daemon_config {
                 get_daemon_wasm_args(cfg.daemon.wasm.clone())
             } else {
@@ -82,49 +114,87 @@ fn main() -> Result<()> {
         }
     };
 
-    let task_rt = TaskRuntime::new()?;
+    let task_rt = TaskRuntime::new(cfg.runtime.clone())?;
 
     if let Some(args) = wasm_with_args {
         let task = Task {
             id: None,
             wasm_component: fs::read(&args[0])?,
+            signature: signature::read_detached(&args[0])?,
             wasm_component_args: args,
             end_time: Utc.with_ymd_and_hms(3000, 1, 1, 1, 1, 1).unwrap(),
+            envs: vec![],
+            limits: None,
+            preopens: None,
+            allow_precompiled: true,
+            capture_output: None,
         };
         task_rt.schedule(task)?;
     };
 
-    thread::spawn(move || -> Result<()> {
+    let grace_period = Duration::from_secs(cfg.runtime.shutdown_grace_period);
+    let res = thread::spawn(move || -> Result<()> {
         let rt = tokio::runtime::Runtime::new()?;
-        let tasks = async_tasks(cfg.remote, task_rt);
-        rt.block_on(tasks)?;
-        Ok(())
+        let tasks = async_tasks(cfg.remote, cfg.control, task_rt, grace_period);
+        let res = rt.block_on(tasks);
+        // don't wait for blocking work still running after the grace period
+        rt.shutdown_background();
+        res
     })
     .join()
-    .expect("The async tasks thread has panicked")?;
+    .expect("The async tasks thread has panicked");
 
-    Ok(())
+    if let Some(pid_file) = pid_file {
+        if let Err(e) = fs::remove_file(&pid_file) {
+            tracing::warn!("Failed to remove pid file {}: {}", pid_file, e);
+        }
+    }
+
+    res
 }
 
 #[expect(clippy::significant_drop_tightening)]
-async fn async_tasks(remote_cfg: RemoteConfig, mut task_rt: TaskRuntime) -> Result<()> {
-    let token_cloned = remote_cfg.token.clone();
-    let rpc_task = async move {
-        if !remote_cfg.rpc.enable {
-            let handle = task_rt.spawn(
-                None,
-                remote_cfg.rpc.data_export.buf_size,
-                remote_cfg.rpc.data_export.buf_watermark,
-                "unknown".to_string(),
-            )?;
-            drop(task_rt);
-            handle.join().expect("TaskRuntime has panicked");
-            return Ok(());
+async fn async_tasks(
+    remote_cfg: RemoteConfig,
+    control_cfg: ControlConfig,
+    task_rt: TaskRuntime,
+    grace_period: Duration,
+) -> Result<()> {
+    let task_rt = Arc::new(task_rt);
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sigint = signal(SignalKind::interrupt())?;
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = sigterm.recv() => tracing::info!("Received SIGTERM, shutting down"),
+            _ = sigint.recv() => tracing::info!("Received SIGINT, shutting down"),
         }
+        let _ = shutdown_tx.send(true);
+    });
 
-        let duration = Duration::from_secs(remote_cfg.rpc.heartbeat_interval);
-        let mut client = RpcClient::new(&remote_cfg.rpc, token_cloned).await?;
+    // data is exported to influxdb if enabled, or else through rpc
+    let remote_export = remote_cfg.rpc.enable || remote_cfg.influxdb.enable;
+    let spool_cfg = &remote_cfg.rpc.data_export.spool;
+    let spool = (remote_export && spool_cfg.enable)
+        .then(|| Spool::open(spool_cfg).map(Arc::new))
+        .transpose()?;
+    let export_counters = remote_export.then(|| Arc::new(ExportCounters::default()));
+    let influxdb = remote_cfg
+        .influxdb
+        .enable
+        .then(|| {
+            InfluxClient::new(
+                &remote_cfg.influxdb,
+                export_counters.clone().unwrap_or_default(),
+            )
+        })
+        .transpose()?;
 
+    // the instance id is part of the otlp resource, so it is obtained before building it
+    let rpc = if remote_cfg.rpc.enable {
+        let export_counters = export_counters.clone().unwrap_or_default();
+        let token = remote_cfg.token.clone();
+        let client = RpcClient::new(&remote_cfg.rpc, token, export_counters).await?;
         let instance_id = match fs::read_to_string(&remote_cfg.rpc.instance_id_file).ok() {
             Some(s) => s,
             None => {
@@ -133,64 +203,258 @@ async fn async_tasks(remote_cfg: RemoteConfig, mut task_rt: TaskRuntime) -> Resu
                 instance_id
             }
         };
+        Some((client, instance_id))
+    } else {
+        None
+    };
+    let instance_id = rpc
+        .as_ref()
+        .map(|(_, it)| it.clone())
+        .or_else(|| fs::read_to_string(&remote_cfg.rpc.instance_id_file).ok());
+
+    // system metrics are pushed over otlp and/or scraped by prometheus
+    let prometheus_registry = remote_cfg
+        .prometheus
+        .enable
+        .then(otlp::PrometheusRegistry::default);
+    let otlp = (remote_cfg.otlp.enable || prometheus_registry.is_some())
+        .then(|| {
+            otlp::Otlp::new(
+                &remote_cfg.token,
+                Duration::from_secs(remote_cfg.otlp.interval),
+                remote_cfg.otlp.enable.then_some(&remote_cfg.otlp),
+                prometheus_registry.as_ref(),
+                otlp::resource(
+                    instance_id.map(|it| it.trim().to_owned()),
+                    &remote_cfg.otlp.resource,
+                ),
+            )
+        })
+        .transpose()?;
+    let guest_metrics = otlp
+        .as_ref()
+        .filter(|_| remote_cfg.otlp.guest_metrics)
+        .map(|it| {
+            let cfg = &remote_cfg.otlp;
+            let counters = cfg.guest_counters.clone();
+            Arc::new(it.guest_metrics(counters, cfg.guest_max_metrics, cfg.guest_max_total_metrics))
+        });
+
+    let control_enabled = control_cfg.enable;
+    let control_task = {
+        let task_rt = task_rt.clone();
+        let spool = spool.clone();
+        let shutdown = shutdown_rx.clone();
+        async move {
+            if !control_cfg.enable {
+                return Ok(());
+            }
+            control::serve(control_cfg, task_rt, spool, export_counters, shutdown).await
+        }
+    };
+
+    let prometheus_task = {
+        let shutdown = shutdown_rx.clone();
+        let prometheus_cfg = remote_cfg.prometheus;
+        async move {
+            let Some(registry) = prometheus_registry else {
+                return Ok(());
+            };
+            prometheus::serve(prometheus_cfg, registry, shutdown).await
+        }
+    };
+
+    let mut shutdown = shutdown_rx.clone();
+    let rpc_task = async move {
+        let Some((client, instance_id)) = rpc else {
+            let sink = influxdb.map(Sink::Influxdb);
+            let handle = task_rt.spawn(
+                sink.clone(),
+                spool.clone(),
+                remote_cfg.rpc.data_export,
+                guest_metrics,
+                "unknown".to_string(),
+            )?;
+            let replay = spool
+                .zip(sink)
+                .map(|(spool, sink)| tokio::spawn(spool.replay(sink, shutdown.clone())));
+            // keep accepting tasks from the control socket until shutdown
+            if !control_enabled {
+                task_rt.close();
+            }
+            let mut stopped = stopped(handle);
+            tokio::select! {
+                res = &mut stopped => return res.context("TaskRuntime has panicked"),
+                _ = shutdown.wait_for(|it| *it) => {}
+            }
+            task_rt.shutdown();
+            wait_stopped(stopped, grace_period).await?;
+            if let Some(replay) = replay {
+                replay.await?;
+            }
+            return Ok(());
+        };
 
-        task_rt.spawn(
-            Some(client.clone()),
-            remote_cfg.rpc.data_export.buf_size,
-            remote_cfg.rpc.data_export.buf_watermark,
+        let duration = Duration::from_secs(remote_cfg.rpc.heartbeat_interval);
+        let sink = influxdb.map_or_else(|| Sink::Rpc(Box::new(client.clone())), Sink::Influxdb);
+        let handle = task_rt.spawn(
+            Some(sink.clone()),
+            spool.clone(),
+            remote_cfg.rpc.data_export.clone(),
+            guest_metrics,
             instance_id.clone(),
         )?;
+        // reports are for the server, a spool replayed to influxdb would never deliver them
+        let report_spool = spool.clone().filter(|_| matches!(sink, Sink::Rpc(_)));
+        let replay = spool
+            .clone()
+            .map(|it| tokio::spawn(it.replay(sink, shutdown.clone())));
         client.send_host_info(instance_id.clone()).await?;
-        loop {
+        let mut undone = vec![];
+        while !*shutdown.borrow() {
             let idle = task_rt.is_idle();
-            if idle {
-                if let Some(mut task) = client.get_task(instance_id.clone()).await? {
-                    let task_id = task
-                        .id
-                        .as_ref()
-                        .map(|it| it.to_string())
-                        .expect("No task id provided");
-                    task.wasm_component_args.insert(0, task_id);
-                    task_rt.schedule(task)?
+            if task_rt.has_capacity() {
+                match client.get_task(instance_id.clone()).await? {
+                    Some(Ok(mut task)) => {
+                        let task_id = task
+                            .id
+                            .as_ref()
+                            .map(|it| it.to_string())
+                            .expect("No task id provided");
+                        task.wasm_component_args.insert(0, task_id.clone());
+                        if let Err(e) = task_rt.schedule(task) {
+                            tracing::warn!("Ignored task {}: {}", task_id, e);
+                        }
+                    }
+                    Some(Err(report)) => {
+                        tracing::warn!(
+                            "Rejected task {}: {}",
+                            report.key,
+                            report.error.as_deref().unwrap_or_default()
+                        );
+                        task_rt.reject(report);
+                    }
+                    None => {}
                 }
             }
 
-            client
+            let cancelled = client
                 .heartbeat(HeartbeatReq {
                     instance_id: instance_id.clone(),
                     idle,
                 })
                 .await?;
+            for task_id in cancelled {
+                if task_rt.cancel(&task_id) {
+                    tracing::info!("Cancelled task {} on request of the server", task_id);
+                }
+            }
+
+            let finished = task_rt.finished_tasks();
+            report_finished(
+                &client,
+                &instance_id,
+                report_spool.as_deref(),
+                finished,
+                &mut undone,
+            )
+            .await;
 
-            if let Some(id) = task_rt.finished_task_id() {
-                let _ = client.task_done(id).await;
+            tokio::select! {
+                _ = tokio::time::sleep(duration) => {}
+                _ = shutdown.wait_for(|it| *it) => {}
             }
+        }
 
-            tokio::time::sleep(duration).await;
+        task_rt.shutdown();
+        wait_stopped(stopped(handle), grace_period).await?;
+        let finished = task_rt.finished_tasks();
+        report_finished(
+            &client,
+            &instance_id,
+            report_spool.as_deref(),
+            finished,
+            &mut undone,
+        )
+        .await;
+        if let Some(replay) = replay {
+            replay.await?;
         }
-        #[allow(unreachable_code)]
         Ok::<(), Error>(())
     };
 
-    let otlp_task = async {
-        if !remote_cfg.otlp.enable {
+    let mut shutdown = shutdown_rx;
+    let otlp_cfg = remote_cfg.otlp;
+    let otlp_task = async move {
+        let Some(otlp) = otlp else {
             return Ok(());
-        }
-        let export_conf = ExportConfig {
-            endpoint: Some(remote_cfg.otlp.addr),
-            ..Default::default()
         };
-        let otlp = otlp::Otlp::new(
-            remote_cfg.token,
-            Duration::from_secs(remote_cfg.otlp.interval),
-            export_conf,
-        )?;
 
-        otlp.otlp_tasks().await?;
+        tokio::select! {
+            res = otlp.otlp_tasks(&otlp_cfg) => res?,
+            _ = shutdown.wait_for(|it| *it) => {}
+        }
+        if let Err(e) = otlp.shutdown().await {
+            tracing::error!("Failed to flush otlp metrics: {e}");
+        }
         Ok::<(), Error>(())
     };
 
-    try_join!(rpc_task, otlp_task)?;
+    try_join!(rpc_task, otlp_task, control_task, prometheus_task)?;
+
+    Ok(())
+}
+
+/// Wait for the thread spawned by `TaskRuntime` without blocking the async runtime.
+/// The receiver errs if `TaskRuntime` has panicked.
+fn stopped(handle: thread::JoinHandle<()>) -> oneshot::Receiver<()> {
+    let (tx, rx) = oneshot::channel();
+    // a detached thread, so giving up on it doesn't hold the async runtime
+    thread::spawn(move || {
+        if handle.join().is_ok() {
+            let _ = tx.send(());
+        }
+    });
+    rx
+}
 
+async fn wait_stopped(stopped: oneshot::Receiver<()>, grace_period: Duration) -> Result<()> {
+    match tokio::time::timeout(grace_period, stopped).await {
+        Ok(res) => res.context("TaskRuntime has panicked")?,
+        Err(_) => tracing::warn!(
+            "Tasks did not stop within {}s, their pending data is lost",
+            grace_period.as_secs()
+        ),
+    }
     Ok(())
 }
+
+/// Heartbeats a task is retried to be marked as done before giving up.
+const TASK_DONE_ATTEMPTS: usize = 5;
+
+/// Report the finished server tasks and mark them as done, even if their report could not be sent.
+/// Tasks that fail to be marked as done are kept in `undone` for the next call.
+async fn report_finished(
+    client: &RpcClient,
+    instance_id: &str,
+    spool: Option<&Spool>,
+    reports: Vec<TaskReport>,
+    undone: &mut Vec<(String, usize)>,
+) {
+    for report in reports {
+        client.send_report(instance_id, &report, spool).await;
+        undone.push((report.key, 0));
+    }
+    let mut retry = vec![];
+    for (task_id, attempts) in undone.drain(..) {
+        match client.task_done(task_id.clone()).await {
+            Ok(()) => {}
+            Err(e) if attempts + 1 < TASK_DONE_ATTEMPTS => {
+                tracing::warn!("Failed to mark task {} as done, will retry: {}", task_id, e);
+                retry.push((task_id, attempts + 1));
+            }
+            Err(e) => tracing::error!("Failed to mark task {} as done: {}", task_id, e),
+        }
+    }
+    *undone = retry;
+}
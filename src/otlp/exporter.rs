@@ -0,0 +1,143 @@
+// Copyright (c) 2023-2024 Optimatist Technology Co., Ltd. All rights reserved.
+// DO NOT ALTER OR REMOVE COPYRIGHT NOTICES OR THIS FILE HEADER.
+//
+// This file is part of PSH.
+//
+// PSH is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License
+// as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+//
+// PSH is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even
+// the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License along with Performance Savior Home (PSH). If not,
+// see <https://www.gnu.org/licenses/>.
+
+use std::{fs, io::Write, time::Duration};
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use flate2::write::GzEncoder;
+use opentelemetry_http::{Bytes, HttpClient, HttpError, Request, Response};
+use opentelemetry_otlp::{
+    Compression, MetricExporter, Protocol, WithExportConfig, WithHttpConfig, WithTonicConfig,
+};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_ENCODING};
+use tonic::{
+    metadata::{MetadataKey, MetadataMap},
+    transport::{Certificate, ClientTlsConfig, Identity},
+};
+
+use crate::config::{self, OtlpConfig, OtlpProtocol, OtlpTlsConfig};
+
+/// Exporter pushing the metrics to `cfg.addr`, the token is sent as a bearer authorization header.
+pub fn metric_exporter(cfg: &OtlpConfig, token: &str) -> Result<MetricExporter> {
+    let timeout = Duration::from_secs(cfg.timeout);
+    let headers = std::iter::once(("authorization".to_owned(), format!("Bearer {}", token))).chain(
+        cfg.headers
+            .iter()
+            .map(|(k, v)| (k.to_ascii_lowercase(), v.clone())),
+    );
+
+    let exporter = match cfg.protocol {
+        OtlpProtocol::Grpc => {
+            let mut meta = MetadataMap::new();
+            for (k, v) in headers {
+                meta.insert(MetadataKey::from_bytes(k.as_bytes())?, v.parse()?);
+            }
+            let mut builder = MetricExporter::builder()
+                .with_tonic()
+                .with_endpoint(&cfg.addr)
+                .with_timeout(timeout)
+                .with_metadata(meta);
+            if !cfg.tls.insecure {
+                builder = builder.with_tls_config(tonic_tls(&cfg.tls)?);
+            }
+            match cfg.compression {
+                config::Compression::None => {}
+                config::Compression::Gzip => builder = builder.with_compression(Compression::Gzip),
+                config::Compression::Zstd => builder = builder.with_compression(Compression::Zstd),
+            }
+            builder.build()?
+        }
+        OtlpProtocol::HttpProtobuf => MetricExporter::builder()
+            .with_http()
+            .with_http_client(HttpExportClient::new(cfg, headers)?)
+            .with_endpoint(format!("{}/v1/metrics", cfg.addr.trim_end_matches('/')))
+            .with_protocol(Protocol::HttpBinary)
+            .with_timeout(timeout)
+            .build()?,
+    };
+    Ok(exporter)
+}
+
+fn tonic_tls(cfg: &OtlpTlsConfig) -> Result<ClientTlsConfig> {
+    let mut tls = match &cfg.ca_file {
+        Some(path) => ClientTlsConfig::new().ca_certificate(Certificate::from_pem(read(path)?)),
+        None => ClientTlsConfig::new().with_native_roots(),
+    };
+    if let (Some(cert), Some(key)) = (&cfg.cert_file, &cfg.key_file) {
+        tls = tls.identity(Identity::from_pem(read(cert)?, read(key)?));
+    }
+    Ok(tls)
+}
+
+fn read(path: &str) -> Result<Vec<u8>> {
+    fs::read(path).with_context(|| format!("failed to read {}", path))
+}
+
+/// Client of the http/protobuf exporter, adding all the configured headers to each request
+/// and gzipping the body when `compression` is gzip, which the upstream client does not.
+#[derive(Debug)]
+struct HttpExportClient {
+    client: reqwest::Client,
+    headers: HeaderMap,
+    gzip: bool,
+}
+
+impl HttpExportClient {
+    fn new(cfg: &OtlpConfig, headers: impl Iterator<Item = (String, String)>) -> Result<Self> {
+        let mut builder = reqwest::Client::builder().timeout(Duration::from_secs(cfg.timeout));
+        if let Some(path) = &cfg.tls.ca_file {
+            builder = builder.tls_built_in_root_certs(false);
+            for cert in reqwest::Certificate::from_pem_bundle(&read(path)?)? {
+                builder = builder.add_root_certificate(cert);
+            }
+        }
+        if let (Some(cert), Some(key)) = (&cfg.tls.cert_file, &cfg.tls.key_file) {
+            let mut pem = read(cert)?;
+            pem.push(b'\n');
+            pem.extend(read(key)?);
+            builder = builder.identity(reqwest::Identity::from_pem(&pem)?);
+        }
+        if let Some(proxy) = &cfg.proxy {
+            builder = builder.proxy(reqwest::Proxy::all(proxy)?);
+        }
+
+        let mut map = HeaderMap::new();
+        for (k, v) in headers {
+            map.insert(HeaderName::from_bytes(k.as_bytes())?, v.parse()?);
+        }
+        Ok(Self {
+            client: builder.build()?,
+            headers: map,
+            gzip: cfg.compression == config::Compression::Gzip,
+        })
+    }
+}
+
+#[async_trait]
+impl HttpClient for HttpExportClient {
+    async fn send(&self, request: Request<Vec<u8>>) -> Result<Response<Bytes>, HttpError> {
+        let (mut parts, mut body) = request.into_parts();
+        parts.headers.extend(self.headers.clone());
+        if self.gzip {
+            let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(&body)?;
+            body = encoder.finish()?;
+            parts
+                .headers
+                .insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+        }
+        self.client.send(Request::from_parts(parts, body)).await
+    }
+}
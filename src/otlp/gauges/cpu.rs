@@ -12,167 +12,169 @@
 // You should have received a copy of the GNU Lesser General Public License along with Performance Savior Home (PSH). If not,
 // see <https://www.gnu.org/licenses/>.
 
-use opentelemetry::metrics::ObservableGauge;
+use std::sync::Arc;
+
+use opentelemetry::metrics::{ObservableCounter, ObservableGauge};
 use opentelemetry::KeyValue;
-use psh_system::cpu::CpuHandle;
+use psh_system::cpu::{CpuHandle, CpuStats, CpuTime};
+
+use super::{rates::Deltas, Group, Source};
+
+/// Readings of `/proc/stat` shared by the cpu instruments.
+pub fn source(group: Group) -> Arc<Source<CpuStats>> {
+    let cpu = CpuHandle::new();
+    Source::new(group, move |interval| cpu.stat(Some(interval)).ok())
+}
+
+/// Ticks the CPU has been busy and in total, guest time is included in user and nice.
+fn busy_total(time: &CpuTime) -> Vec<u64> {
+    let busy = time.user
+        + time.nice
+        + time.system
+        + time.irq.unwrap_or(0)
+        + time.softirq.unwrap_or(0)
+        + time.steal.unwrap_or(0);
+    vec![busy, busy + time.idle + time.iowait.unwrap_or(0)]
+}
 
 impl super::super::Otlp {
-    pub fn cpu_gauges(&self) -> anyhow::Result<ObservableGauge<u64>> {
-        let cpu = CpuHandle::new();
-        let token = self.token.clone();
-        let interval = self.interval;
+    pub fn cpu_gauges(
+        &self,
+        source: &Arc<Source<CpuStats>>,
+    ) -> anyhow::Result<ObservableGauge<u64>> {
+        let source = source.clone();
 
         let gauge = self
             .meter
             .u64_observable_gauge("CpuStat")
-            .with_description("System profile cpu statistics.")
+            .with_description(
+                "Boot time in seconds since the Epoch (btime), processes runnable (procs_running) \
+                 and blocked waiting for I/O (procs_blocked).",
+            )
             .with_callback(move |gauge| {
-                let Ok(cpus) = cpu.stat(Some(interval)) else {
+                let Some((_, cpus)) = source.get() else {
+                    return;
+                };
+
+                let gauges = [
+                    (cpus.btime, "btime"),
+                    (cpus.procs_running.unwrap_or(0).into(), "procs_running"),
+                    (cpus.procs_blocked.unwrap_or(0).into(), "procs_blocked"),
+                ];
+                for (m, stat) in gauges {
+                    gauge.observe(m, &[KeyValue::new("stat", stat)]);
+                }
+            })
+            .build();
+        Ok(gauge)
+    }
+
+    pub fn cpu_time_counters(
+        &self,
+        source: &Arc<Source<CpuStats>>,
+    ) -> anyhow::Result<ObservableCounter<u64>> {
+        let source = source.clone();
+
+        let counter = self
+            .meter
+            .u64_observable_counter("CpuTime")
+            .with_unit("ms")
+            .with_description("Time each CPU has spent in specific states.")
+            .with_callback(move |counter| {
+                let Some((_, cpus)) = source.get() else {
                     return;
                 };
 
-                let desc =
-                    "The amount of time, measured in ticks, the CPU has been in specific states";
                 for (cpu, cpu_time) in cpus.per_cpu.into_iter().enumerate() {
-                    let gauges = [
-                        (
-                            cpus.ctxt,
-                            [
-                                KeyValue::new("cpu", cpu as i64),
-                                KeyValue::new("stat", "ctxt"),
-                                KeyValue::new("desc", "context switches that the system underwent"),
-                            ],
-                        ),
-                        (
-                            cpus.btime,
-                            [
-                                KeyValue::new("cpu", cpu as i64),
-                                KeyValue::new("stat", "btime"),
-                                KeyValue::new(
-                                    "desc",
-                                    "Boot time, in number of seconds since the Epoch",
-                                ),
-                            ],
-                        ),
-                        (
-                            cpus.processes,
-                            [
-                                KeyValue::new("cpu", cpu as i64),
-                                KeyValue::new("stat", "processes"),
-                                KeyValue::new("desc", "Number of forks since boot"),
-                            ],
-                        ),
-                        (
-                            cpus.procs_running.unwrap_or(0).into(),
-                            [
-                                KeyValue::new("cpu", cpu as i64),
-                                KeyValue::new("stat", "procs_running"),
-                                KeyValue::new("desc", "Number of processes in runnable state"),
-                            ],
-                        ),
-                        (
-                            cpus.procs_blocked.unwrap_or(0).into(),
-                            [
-                                KeyValue::new("cpu", cpu as i64),
-                                KeyValue::new("stat", "procs_blocked"),
-                                KeyValue::new(
-                                    "desc",
-                                    "Number of processes blocked waiting for I/O",
-                                ),
-                            ],
-                        ),
+                    let counters = [
+                        (Some(cpu_time.user_ms()), "user"),
+                        (Some(cpu_time.nice_ms()), "nice"),
+                        (Some(cpu_time.system_ms()), "system"),
+                        (Some(cpu_time.idle_ms()), "idle"),
+                        (cpu_time.iowait_ms(), "iowait"),
+                        (cpu_time.irq_ms(), "irq"),
+                        (cpu_time.softirq_ms(), "softirq"),
+                        (cpu_time.steal_ms(), "steal"),
+                        (cpu_time.guest_ms(), "guest"),
+                        (cpu_time.guest_nice_ms(), "guest_nice"),
                     ];
-                    gauges.into_iter().for_each(|(m, [kv1, kv2, kv3])| {
-                        let a = &[KeyValue::new("token", token.clone()), kv1, kv2, kv3];
-                        gauge.observe(m, a);
-                    });
-
-                    macro_rules! gauges {
-                        ($($item:ident,)+) => {
-                            [
-                                $((
-                                    cpu_time.$item,
-                                    [
-                                        KeyValue::new("cpu", cpu as i64),
-                                        KeyValue::new("stat", stringify!($item)),
-                                        KeyValue::new("desc", desc),
-                                    ],
-                                ),)*
-                            ]
+                    for (m, stat) in counters {
+                        let Some(m) = m else {
+                            continue;
                         };
+                        let a = &[
+                            KeyValue::new("cpu", cpu as i64),
+                            KeyValue::new("stat", stat),
+                        ];
+                        counter.observe(m, a);
                     }
-                    let gauges = gauges![system, idle, user, nice,];
-                    gauges.into_iter().for_each(|(m, [kv1, kv2, kv3])| {
-                        let a = &[KeyValue::new("token", token.clone()), kv1, kv2, kv3];
-                        gauge.observe(m, a);
-                    });
-
-                    macro_rules! gauges {
-                        ($($item:ident,)+) => {
-                            [
-                                $((
-                                    cpu_time.$item(),
-                                    [
-                                        KeyValue::new("cpu", cpu as i64),
-                                        KeyValue::new("stat", stringify!($item)),
-                                        KeyValue::new("desc", desc),
-                                    ],
-                                ),)*
-                            ]
-                        };
-                    }
-                    let gauges = gauges![user_ms, nice_ms, system_ms, idle_ms,];
-                    gauges.into_iter().for_each(|(m, [kv1, kv2, kv3])| {
-                        let a = &[KeyValue::new("token", token.clone()), kv1, kv2, kv3];
-                        gauge.observe(m, a);
-                    });
-
-                    macro_rules! gauges {
-                        ($($item_o:ident,)+) => {
-                            [
-                                $((
-                                    cpu_time.$item_o.unwrap_or(0),
-                                    [
-                                        KeyValue::new("cpu", cpu as i64),
-                                        KeyValue::new("stat", stringify!($item_o)),
-                                        KeyValue::new("desc", desc),
-                                    ],
-                                ),)*
-                            ]
-                        };
-                    }
-                    let gauges = gauges![iowait, irq, softirq, steal, guest, guest_nice,];
-                    gauges.into_iter().for_each(|(m, [kv1, kv2, kv3])| {
-                        let a = &[KeyValue::new("token", token.clone()), kv1, kv2, kv3];
-                        gauge.observe(m, a);
-                    });
-
-                    macro_rules! gauges {
-                        ($($item_o:ident,)+) => {
-                            [
-                                $((
-                                    cpu_time.$item_o().unwrap_or(0),
-                                    [
-                                        KeyValue::new("cpu", cpu as i64),
-                                        KeyValue::new("stat", stringify!($item_o)),
-                                        KeyValue::new("desc", desc),
-                                    ],
-                                ),)*
-                            ]
-                        };
+                }
+            })
+            .build();
+        Ok(counter)
+    }
+
+    pub fn cpu_activity_counters(
+        &self,
+        source: &Arc<Source<CpuStats>>,
+    ) -> anyhow::Result<ObservableCounter<u64>> {
+        let source = source.clone();
+
+        let counter = self
+            .meter
+            .u64_observable_counter("CpuActivity")
+            .with_description("Context switches (ctxt) and forks (processes) since boot.")
+            .with_callback(move |counter| {
+                let Some((_, cpus)) = source.get() else {
+                    return;
+                };
+
+                for (m, stat) in [(cpus.ctxt, "ctxt"), (cpus.processes, "processes")] {
+                    let a = &[KeyValue::new("stat", stat)];
+                    counter.observe(m, a);
+                }
+            })
+            .build();
+        Ok(counter)
+    }
+
+    pub fn cpu_utilization_gauges(
+        &self,
+        source: &Arc<Source<CpuStats>>,
+    ) -> anyhow::Result<ObservableGauge<f64>> {
+        let source = source.clone();
+        // `None` for all CPUs
+        let deltas = Deltas::<Option<usize>>::new();
+
+        let gauge = self
+            .meter
+            .f64_observable_gauge("CpuUtilization")
+            .with_unit("%")
+            .with_description(
+                "Percentage of time the CPU has been busy since the last observation.",
+            )
+            .with_callback(move |gauge| {
+                let Some((at, cpus)) = source.get() else {
+                    return;
+                };
+
+                let per_cpu = cpus
+                    .per_cpu
+                    .iter()
+                    .enumerate()
+                    .map(|(cpu, it)| (Some(cpu), it));
+                for (cpu, cpu_time) in std::iter::once((None, &cpus.total)).chain(per_cpu) {
+                    let Some((d, _)) = deltas.update(cpu, at, busy_total(cpu_time)) else {
+                        continue;
+                    };
+                    if d[1] == 0 {
+                        continue;
                     }
-                    let gauges = gauges![
-                        iowait_ms,
-                        irq_ms,
-                        softirq_ms,
-                        steal_ms,
-                        guest_ms,
-                        guest_nice_ms,
-                    ];
-                    gauges.into_iter().for_each(|(m, [kv1, kv2, kv3])| {
-                        let a = &[KeyValue::new("token", token.clone()), kv1, kv2, kv3];
-                        gauge.observe(m, a);
-                    });
+                    let a: Vec<_> = cpu
+                        .map(|it| KeyValue::new("cpu", it as i64))
+                        .into_iter()
+                        .collect();
+                    gauge.observe(d[0] as f64 * 100.0 / d[1] as f64, &a);
                 }
             })
             .build();
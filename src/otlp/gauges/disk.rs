@@ -12,39 +12,79 @@
 // You should have received a copy of the GNU Lesser General Public License along with Performance Savior Home (PSH). If not,
 // see <https://www.gnu.org/licenses/>.
 
-use opentelemetry::metrics::ObservableGauge;
+use std::sync::Arc;
+
+use opentelemetry::metrics::{ObservableCounter, ObservableGauge};
 use opentelemetry::KeyValue;
-use psh_system::disk::DiskHandle;
+use psh_system::disk::{DiskHandle, DiskStat};
+
+use super::{rates::Deltas, Group, Source};
+
+/// Size of the sectors counted in `/proc/diskstats`, whatever the device uses.
+const SECTOR_SIZE: f64 = 512.0;
+
+/// Readings of `/proc/diskstats` shared by the disk instruments.
+pub fn source(group: Group) -> Arc<Source<Vec<DiskStat>>> {
+    let disk = DiskHandle::new();
+    Source::new(group, move |interval| disk.stat(Some(interval)).ok())
+}
 
 impl super::super::Otlp {
-    pub fn disk_gagues(&self) -> anyhow::Result<ObservableGauge<u64>> {
-        let token = self.token.clone();
-        let interval = self.interval;
-        let disk = DiskHandle::new();
+    pub fn disk_gagues(
+        &self,
+        source: &Arc<Source<Vec<DiskStat>>>,
+    ) -> anyhow::Result<ObservableGauge<u64>> {
+        let source = source.clone();
 
         let gauge = self
             .meter
             .u64_observable_gauge("DiskStat")
             .with_description("System profile disk statistics.")
             .with_callback(move |gauge| {
-                let Ok(disks) = disk.stat(Some(interval)) else {
+                let Some((_, disks)) = source.get() else {
                     return;
                 };
-                for stat in disks {
+                for stat in disks
+                    .into_iter()
+                    .filter(|it| source.group.allows_device(&it.name))
+                {
+                    let a = &[
+                        KeyValue::new("disk", stat.name),
+                        KeyValue::new("stat", "in_progress"),
+                    ];
+                    gauge.observe(stat.in_progress, a);
+                }
+            })
+            .build();
+        Ok(gauge)
+    }
+
+    pub fn disk_counters(
+        &self,
+        source: &Arc<Source<Vec<DiskStat>>>,
+    ) -> anyhow::Result<ObservableCounter<u64>> {
+        let source = source.clone();
+
+        let counter = self
+            .meter
+            .u64_observable_counter("DiskIo")
+            .with_description("Disk I/O counters of /proc/diskstats, times are in ms.")
+            .with_callback(move |counter| {
+                let Some((_, disks)) = source.get() else {
+                    return;
+                };
+                for stat in disks
+                    .into_iter()
+                    .filter(|it| source.group.allows_device(&it.name))
+                {
                     let name = stat.name;
 
-                    macro_rules! gauges {
+                    macro_rules! counters {
                         ($($stat:ident,)+) => {
-                            [$((
-                                stat.$stat,
-                                [
-                                    KeyValue::new("disk", name.clone()),
-                                    KeyValue::new("stat", stringify!($stat)),
-                                ],
-                            ),)*]
+                            [$((Some(stat.$stat), stringify!($stat)),)*]
                         };
                     }
-                    let gauges = gauges![
+                    let counters = counters![
                         reads,
                         merged,
                         sectors_read,
@@ -53,27 +93,16 @@ impl super::super::Otlp {
                         writes_merged,
                         sectors_written,
                         time_writing,
-                        in_progress,
                         time_in_progress,
                         weighted_time_in_progress,
                     ];
-                    gauges.into_iter().for_each(|(m, [kv1, kv2])| {
-                        let a = &[KeyValue::new("token", token.clone()), kv1, kv2];
-                        gauge.observe(m, a);
-                    });
 
-                    macro_rules! gauges {
+                    macro_rules! counters_opt {
                         ($($stat:ident,)+) => {
-                            [$((
-                                stat.$stat.unwrap_or(0),
-                                [
-                                    KeyValue::new("disk", name.clone()),
-                                    KeyValue::new("stat", stringify!($stat)),
-                                ],
-                            ),)*]
+                            [$((stat.$stat, stringify!($stat)),)*]
                         };
                     }
-                    let gauges = gauges![
+                    let optional = counters_opt![
                         discards,
                         discards_merged,
                         sectors_discarded,
@@ -81,10 +110,76 @@ impl super::super::Otlp {
                         flushes,
                         time_flushing,
                     ];
-                    gauges.into_iter().for_each(|(m, [kv1, kv2])| {
-                        let a = &[KeyValue::new("token", token.clone()), kv1, kv2];
+                    for (m, stat) in counters.into_iter().chain(optional) {
+                        let Some(m) = m else {
+                            continue;
+                        };
+                        let a = &[
+                            KeyValue::new("disk", name.clone()),
+                            KeyValue::new("stat", stat),
+                        ];
+                        counter.observe(m, a);
+                    }
+                }
+            })
+            .build();
+        Ok(counter)
+    }
+
+    pub fn disk_rate_gauges(
+        &self,
+        source: &Arc<Source<Vec<DiskStat>>>,
+    ) -> anyhow::Result<ObservableGauge<f64>> {
+        let source = source.clone();
+        let deltas = Deltas::<String>::new();
+
+        let gauge = self
+            .meter
+            .f64_observable_gauge("DiskRate")
+            .with_description(
+                "Disk throughput, latency and utilization since the last observation.",
+            )
+            .with_callback(move |gauge| {
+                let Some((at, disks)) = source.get() else {
+                    return;
+                };
+                // forget the disks that are gone, such as detached loop devices
+                deltas.retain(|name| disks.iter().any(|it| it.name == *name));
+                for stat in disks
+                    .into_iter()
+                    .filter(|it| source.group.allows_device(&it.name))
+                {
+                    let counters = vec![
+                        stat.reads,
+                        stat.writes,
+                        stat.sectors_read,
+                        stat.sectors_written,
+                        stat.time_reading,
+                        stat.time_writing,
+                        stat.time_in_progress,
+                    ];
+                    let Some((d, secs)) = deltas.update(stat.name.clone(), at, counters) else {
+                        continue;
+                    };
+                    let d: Vec<_> = d.into_iter().map(|it| it as f64).collect();
+                    // average time of the requests completed since the last observation
+                    let await_ms = |time: f64, ops: f64| if ops > 0.0 { time / ops } else { 0.0 };
+                    let rates = [
+                        (d[0] / secs, "read_ops_per_sec"),
+                        (d[1] / secs, "write_ops_per_sec"),
+                        (d[2] * SECTOR_SIZE / secs, "read_bytes_per_sec"),
+                        (d[3] * SECTOR_SIZE / secs, "write_bytes_per_sec"),
+                        (await_ms(d[4], d[0]), "read_await_ms"),
+                        (await_ms(d[5], d[1]), "write_await_ms"),
+                        ((d[6] / 10.0 / secs).min(100.0), "busy_percent"),
+                    ];
+                    for (m, rate) in rates {
+                        let a = &[
+                            KeyValue::new("disk", stat.name.clone()),
+                            KeyValue::new("stat", rate),
+                        ];
                         gauge.observe(m, a);
-                    });
+                    }
                 }
             })
             .build();
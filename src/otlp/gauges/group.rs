@@ -0,0 +1,113 @@
+// Copyright (c) 2023-2024 Optimatist Technology Co., Ltd. All rights reserved.
+// DO NOT ALTER OR REMOVE COPYRIGHT NOTICES OR THIS FILE HEADER.
+//
+// This file is part of PSH.
+//
+// PSH is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License
+// as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+//
+// PSH is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even
+// the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License along with Performance Savior Home (PSH). If not,
+// see <https://www.gnu.org/licenses/>.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+
+use crate::config::{DeviceFilter, MetricGroupConfig};
+
+/// Settings of a group of metrics.
+#[derive(Clone)]
+pub struct Group {
+    pub interval: Duration,
+    devices: DeviceFilter,
+    fields: Vec<String>,
+}
+
+impl Group {
+    pub fn new(cfg: &MetricGroupConfig, default_interval: Duration) -> Result<Self> {
+        Ok(Self {
+            interval: cfg.interval.map_or(default_interval, Duration::from_secs),
+            devices: cfg.devices()?,
+            fields: cfg.fields.clone(),
+        })
+    }
+
+    pub fn allows_device(&self, name: &str) -> bool {
+        self.devices.allows(name)
+    }
+
+    pub fn allows_field(&self, name: &str) -> bool {
+        self.fields.is_empty() || self.fields.iter().any(|it| it == name)
+    }
+
+    /// The first of the allowed fields that is not one of `known`.
+    pub fn unknown_field(&self, known: &[&str]) -> Option<&str> {
+        self.fields
+            .iter()
+            .map(String::as_str)
+            .find(|it| !known.contains(it))
+    }
+
+    pub const fn cached<T>(&self) -> Cached<T> {
+        Cached {
+            interval: self.interval,
+            last: Mutex::new(None),
+        }
+    }
+}
+
+/// Latest reading of a group, observed until the interval of the group has passed.
+pub struct Cached<T> {
+    interval: Duration,
+    last: Mutex<Option<(Instant, T)>>,
+}
+
+impl<T: Clone> Cached<T> {
+    /// The reading and when it was taken, `read` is only called if the last one is outdated.
+    pub fn get(&self, read: impl FnOnce() -> Option<T>) -> Option<(Instant, T)> {
+        let mut last = self.last.lock().unwrap();
+        // collections are not exactly one interval apart
+        match &*last {
+            Some((at, it)) if at.elapsed() < self.interval.mul_f64(0.9) => {
+                return Some((*at, it.clone()));
+            }
+            _ => {}
+        }
+        let reading = (Instant::now(), read()?);
+        *last = Some(reading.clone());
+        drop(last);
+        Some(reading)
+    }
+}
+
+/// Source of a group shared by all of its instruments, so it is read once per interval.
+pub struct Source<T> {
+    pub group: Group,
+    cached: Cached<T>,
+    read: Box<dyn Fn(Duration) -> Option<T> + Send + Sync>,
+}
+
+impl<T: Clone> Source<T> {
+    /// `read` takes the interval of the group.
+    pub fn new(
+        group: Group,
+        read: impl Fn(Duration) -> Option<T> + Send + Sync + 'static,
+    ) -> Arc<Self> {
+        Arc::new(Self {
+            cached: group.cached(),
+            group,
+            read: Box::new(read),
+        })
+    }
+
+    /// The latest reading and when it was taken.
+    pub fn get(&self) -> Option<(Instant, T)> {
+        self.cached.get(|| (self.read)(self.group.interval))
+    }
+}
@@ -14,39 +14,45 @@
 
 use std::borrow::Cow;
 
-use opentelemetry::metrics::ObservableGauge;
+use opentelemetry::metrics::ObservableCounter;
 use opentelemetry::KeyValue;
 use psh_system::interrupt::InterruptHandle;
 
+use super::Group;
+
 impl super::super::Otlp {
-    pub fn irq_gauges(&self) -> anyhow::Result<ObservableGauge<u64>> {
-        let token = self.token.clone();
-        let interval = self.interval;
+    pub fn irq_counters(&self, group: &Group) -> anyhow::Result<ObservableCounter<u64>> {
+        let interval = group.interval;
+        let cached = group.cached();
+        let group = group.clone();
         let interrupt = InterruptHandle::new();
 
-        let gauge = self
+        let counter = self
             .meter
-            .u64_observable_gauge("InterruptStat")
-            .with_description("System profile interrupt statistics.")
-            .with_callback(move |gauge| {
-                let Ok(irqs) = interrupt.stat(Some(interval)) else {
+            .u64_observable_counter("Interrupts")
+            .with_description("Interrupts handled by each CPU since boot.")
+            .with_callback(move |counter| {
+                let Some((_, irqs)) = cached.get(|| interrupt.stat(Some(interval)).ok()) else {
                     return;
                 };
 
                 for int in irqs {
+                    let name = format!("{} {}", int.interrupt_type, int.description);
+                    if !group.allows_device(&name) {
+                        continue;
+                    }
                     let desc = Cow::from(int.description);
                     for (cpu, &cnt) in int.cpu_counts.iter().enumerate() {
                         let a = [
-                            KeyValue::new("token", token.clone()),
                             KeyValue::new("desc", desc.clone()),
                             KeyValue::new("cpu", cpu as i64),
                             KeyValue::new("type", int.interrupt_type.to_string()),
                         ];
-                        gauge.observe(cnt, &a)
+                        counter.observe(cnt, &a)
                     }
                 }
             })
             .build();
-        Ok(gauge)
+        Ok(counter)
     }
 }
@@ -12,14 +12,99 @@
 // You should have received a copy of the GNU Lesser General Public License along with Performance Savior Home (PSH). If not,
 // see <https://www.gnu.org/licenses/>.
 
+use anyhow::bail;
 use opentelemetry::metrics::ObservableGauge;
 use opentelemetry::KeyValue;
-use psh_system::memory::MemoryHandle;
+use psh_system::memory::{Meminfo, MemoryHandle};
+
+use super::Group;
+
+macro_rules! fields {
+    ([$($stat:ident,)+] [$($optional:ident,)+]) => {
+        /// Names of the `stat` attribute of `MemoryStat`, the fields accepted by the memory group.
+        const FIELDS: &[&str] = &[$(stringify!($stat),)+ $(stringify!($optional),)+];
+
+        /// Fields missing from `/proc/meminfo` read as 0.
+        fn readings(mem: &Meminfo) -> Vec<(u64, &'static str)> {
+            vec![
+                $((mem.$stat, stringify!($stat)),)+
+                $((mem.$optional.unwrap_or(0), stringify!($optional)),)+
+            ]
+        }
+    };
+}
+
+fields!(
+    [
+        mem_total,
+        mem_free,
+        buffers,
+        cached,
+        swap_cached,
+        active,
+        inactive,
+        swap_total,
+        swap_free,
+        dirty,
+        writeback,
+        mapped,
+        slab,
+        committed_as,
+        vmalloc_total,
+        vmalloc_used,
+        vmalloc_chunk,
+    ]
+    [
+        cma_total,
+        cma_free,
+        hugepages_total,
+        hugepages_free,
+        hugepages_rsvd,
+        hugepages_surp,
+        hugepagesize,
+        hugetlb,
+        per_cpu,
+        hardware_corrupted,
+        anon_hugepages,
+        shmem_hugepages,
+        shmem_pmd_mapped,
+        file_huge_pages,
+        file_pmd_mapped,
+        direct_map_4k,
+        direct_map_2M,
+        direct_map_1G,
+        k_reclaimable,
+        commit_limit,
+        writeback_tmp,
+        mem_available,
+        active_anon,
+        inactive_anon,
+        active_file,
+        inactive_file,
+        unevictable,
+        mlocked,
+        anon_pages,
+        shmem,
+        s_reclaimable,
+        s_unreclaim,
+        kernel_stack,
+        page_tables,
+        nfs_unstable,
+        bounce,
+        z_swap,
+        z_swapped,
+        secondary_page_tables,
+    ]
+);
 
 impl super::super::Otlp {
-    pub fn mem_gauges(&self) -> anyhow::Result<ObservableGauge<u64>> {
-        let interval = self.interval;
-        let token = self.token.clone();
+    pub fn mem_gauges(&self, group: &Group) -> anyhow::Result<ObservableGauge<u64>> {
+        if let Some(it) = group.unknown_field(FIELDS) {
+            bail!("remote.otlp.groups.memory.fields: unknown field {it}, see doc/metrics.md");
+        }
+        let interval = group.interval;
+        let cached = group.cached();
+        let group = group.clone();
         let memory = MemoryHandle::new();
 
         let gauge = self
@@ -27,96 +112,16 @@ impl super::super::Otlp {
             .u64_observable_gauge("MemoryStat")
             .with_description("System profile memory statistics.")
             .with_callback(move |gauge| {
-                let Ok(mem) = memory.stat(Some(interval)) else {
+                let Some((_, mem)) = cached.get(|| memory.stat(Some(interval)).ok()) else {
                     return;
                 };
 
-                macro_rules! gauges {
-                    ($($stat:ident,)+) => {
-                        [
-                            $(
-                            (mem.$stat, KeyValue::new("stat", stringify!($stat))),
-                            )*
-                        ]
-                    };
-                }
-                let gauges = gauges![
-                    mem_total,
-                    mem_free,
-                    buffers,
-                    cached,
-                    swap_cached,
-                    active,
-                    inactive,
-                    swap_total,
-                    swap_free,
-                    dirty,
-                    writeback,
-                    mapped,
-                    slab,
-                    committed_as,
-                    vmalloc_total,
-                    vmalloc_used,
-                    vmalloc_chunk,
-                ];
-                gauges.into_iter().for_each(|(m, kv)| {
-                    gauge.observe(m, &[KeyValue::new("token", token.clone()), kv]);
+                let gauges = readings(&mem)
+                    .into_iter()
+                    .filter(|(_, stat)| group.allows_field(stat));
+                gauges.for_each(|(m, stat)| {
+                    gauge.observe(m, &[KeyValue::new("stat", stat)]);
                 });
-
-                macro_rules! gauges {
-                ($($stat:ident,)+) => {
-                    [
-                        $(
-                        (mem.$stat.unwrap_or(0), KeyValue::new("stat", stringify!($stat))),
-                        )*
-                    ]
-                };
-            }
-                let gauges = gauges![
-                    cma_total,
-                    cma_free,
-                    hugepages_total,
-                    hugepages_free,
-                    hugepages_rsvd,
-                    hugepages_surp,
-                    hugepagesize,
-                    hugetlb,
-                    per_cpu,
-                    hardware_corrupted,
-                    anon_hugepages,
-                    shmem_hugepages,
-                    shmem_pmd_mapped,
-                    file_huge_pages,
-                    file_pmd_mapped,
-                    direct_map_4k,
-                    direct_map_2M,
-                    direct_map_1G,
-                    k_reclaimable,
-                    commit_limit,
-                    writeback_tmp,
-                    mem_available,
-                    active_anon,
-                    inactive_anon,
-                    active_file,
-                    inactive_file,
-                    unevictable,
-                    mlocked,
-                    anon_pages,
-                    shmem,
-                    s_reclaimable,
-                    s_unreclaim,
-                    kernel_stack,
-                    page_tables,
-                    nfs_unstable,
-                    bounce,
-                    z_swap,
-                    z_swapped,
-                    secondary_page_tables,
-                ];
-
-                gauges.into_iter().for_each(|(m, kv)| {
-                    gauge.observe(m, &[KeyValue::new("token", token.clone()), kv]);
-                })
             })
             .build();
         Ok(gauge)
@@ -14,8 +14,13 @@
 
 pub mod cpu;
 pub mod disk;
+mod group;
 pub mod interrupt;
 pub mod memory;
 pub mod network;
+pub mod process;
+mod rates;
 pub mod rps;
 pub mod vmstat;
+
+pub use group::{Group, Source};
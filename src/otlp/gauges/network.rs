@@ -12,48 +12,72 @@
 // You should have received a copy of the GNU Lesser General Public License along with Performance Savior Home (PSH). If not,
 // see <https://www.gnu.org/licenses/>.
 
-use opentelemetry::metrics::ObservableGauge;
+use std::{collections::HashMap, sync::Arc};
+
+use opentelemetry::metrics::{ObservableCounter, ObservableGauge};
 use opentelemetry::KeyValue;
-use psh_system::network::NetworkHandle;
+use psh_system::network::{DeviceStatus, NetworkHandle};
+
+use super::{rates::Deltas, Group, Source};
+
+/// Readings of `/proc/net/dev` shared by the network instruments.
+pub fn source(group: Group) -> Arc<Source<HashMap<String, DeviceStatus>>> {
+    let network = NetworkHandle::new();
+    Source::new(group, move |interval| network.stat(Some(interval)).ok())
+}
 
 impl super::super::Otlp {
-    pub fn net_gauges(&self) -> anyhow::Result<ObservableGauge<u64>> {
-        let interval = self.interval;
-        let token = self.token.clone();
-        let network = NetworkHandle::new();
+    pub fn net_gauges(
+        &self,
+        source: &Arc<Source<HashMap<String, DeviceStatus>>>,
+    ) -> anyhow::Result<ObservableGauge<u64>> {
+        let source = source.clone();
 
         let gauge = self
             .meter
             .u64_observable_gauge("NetworkStat")
             .with_description("System profile network statistics.")
             .with_callback(move |gauge| {
-                let Ok(stat) = network.stat(Some(interval)) else {
+                let Some((_, stat)) = source.get() else {
                     return;
                 };
-                for (dev, status) in stat {
+                for dev in stat.into_keys().filter(|it| source.group.allows_device(it)) {
                     let speed = Self::net_dev_speed(&dev).unwrap_or(0).into();
+                    let a = [
+                        KeyValue::new("interface", dev),
+                        KeyValue::new("stat", "speed"),
+                    ];
+                    gauge.observe(speed, &a);
+                }
+            })
+            .build();
+        Ok(gauge)
+    }
 
-                    macro_rules! gauges {
+    pub fn net_counters(
+        &self,
+        source: &Arc<Source<HashMap<String, DeviceStatus>>>,
+    ) -> anyhow::Result<ObservableCounter<u64>> {
+        let source = source.clone();
+
+        let counter = self
+            .meter
+            .u64_observable_counter("NetworkIo")
+            .with_description("Network interface counters of /proc/net/dev.")
+            .with_callback(move |counter| {
+                let Some((_, stat)) = source.get() else {
+                    return;
+                };
+                for (dev, status) in stat
+                    .into_iter()
+                    .filter(|(dev, _)| source.group.allows_device(dev))
+                {
+                    macro_rules! counters {
                         ($($stat:ident,)+) => {
-                            [
-                                (
-                                    speed,
-                                    [
-                                        KeyValue::new("interface", dev.clone()),
-                                        KeyValue::new("stat", "speed"),
-                                    ]
-                                ),
-                            $((
-                                status.$stat,
-                                [
-                                    KeyValue::new("interface", dev.clone()),
-                                    KeyValue::new("stat", stringify!($stat)),
-                                ],
-                            ),)*
-                            ]
+                            [$((status.$stat, stringify!($stat)),)*]
                         };
                     }
-                    let gauges = gauges![
+                    let counters = counters![
                         recv_bytes,
                         recv_packets,
                         recv_errs,
@@ -71,10 +95,71 @@ impl super::super::Otlp {
                         sent_carrier,
                         sent_compressed,
                     ];
-                    gauges.into_iter().for_each(|(m, [kv1, kv2])| {
-                        let a = [KeyValue::new("token", token.clone()), kv1, kv2];
+                    for (m, stat) in counters {
+                        let a = [
+                            KeyValue::new("interface", dev.clone()),
+                            KeyValue::new("stat", stat),
+                        ];
+                        counter.observe(m, &a);
+                    }
+                }
+            })
+            .build();
+        Ok(counter)
+    }
+
+    pub fn net_rate_gauges(
+        &self,
+        source: &Arc<Source<HashMap<String, DeviceStatus>>>,
+    ) -> anyhow::Result<ObservableGauge<f64>> {
+        let source = source.clone();
+        let deltas = Deltas::<String>::new();
+
+        let gauge = self
+            .meter
+            .f64_observable_gauge("NetworkRate")
+            .with_description("Network throughput and link utilization since the last observation.")
+            .with_callback(move |gauge| {
+                let Some((at, stat)) = source.get() else {
+                    return;
+                };
+                // forget the interfaces that are gone, such as veth of stopped containers
+                deltas.retain(|dev| stat.contains_key(dev));
+                for (dev, status) in stat
+                    .into_iter()
+                    .filter(|(dev, _)| source.group.allows_device(dev))
+                {
+                    let counters = vec![
+                        status.recv_bytes,
+                        status.sent_bytes,
+                        status.recv_packets,
+                        status.sent_packets,
+                    ];
+                    let Some((d, secs)) = deltas.update(dev.clone(), at, counters) else {
+                        continue;
+                    };
+                    let d: Vec<_> = d.into_iter().map(|it| it as f64 / secs).collect();
+                    let mut rates = vec![
+                        (d[0], "recv_bytes_per_sec"),
+                        (d[1], "sent_bytes_per_sec"),
+                        (d[2], "recv_packets_per_sec"),
+                        (d[3], "sent_packets_per_sec"),
+                    ];
+                    // in Mbit/s, unknown for virtual interfaces
+                    if let Some(speed) = Self::net_dev_speed(&dev).filter(|it| *it > 0) {
+                        let bits = d[0].max(d[1]) * 8.0;
+                        rates.push((
+                            bits * 100.0 / (f64::from(speed) * 1e6),
+                            "utilization_percent",
+                        ));
+                    }
+                    for (m, rate) in rates {
+                        let a = [
+                            KeyValue::new("interface", dev.clone()),
+                            KeyValue::new("stat", rate),
+                        ];
                         gauge.observe(m, &a);
-                    })
+                    }
                 }
             })
             .build();
@@ -0,0 +1,400 @@
+// Copyright (c) 2023-2024 Optimatist Technology Co., Ltd. All rights reserved.
+// DO NOT ALTER OR REMOVE COPYRIGHT NOTICES OR THIS FILE HEADER.
+//
+// This file is part of PSH.
+//
+// PSH is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License
+// as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+//
+// PSH is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even
+// the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License along with Performance Savior Home (PSH). If not,
+// see <https://www.gnu.org/licenses/>.
+
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashSet},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use opentelemetry::metrics::{ObservableCounter, ObservableGauge};
+use opentelemetry::{KeyValue, Value};
+use psh_system::{process::ProcessHandle, System};
+use regex::RegexSet;
+
+use super::{group::Cached, rates::Deltas, Group};
+use crate::config::{ProcessGroupConfig, ProcessOrder};
+
+/// Reading of a selected process, or the sum of the processes with the same name.
+struct Sample {
+    attributes: Vec<KeyValue>,
+    /// number of processes in each state
+    states: BTreeMap<String, u64>,
+    threads: u64,
+    rss: u64,
+    vsize: u64,
+    user_ms: u64,
+    system_ms: u64,
+    /// read and written bytes, unreadable for processes of other users without `CAP_SYS_PTRACE`
+    io: Option<(u64, u64)>,
+    cpu_percent: Option<f64>,
+}
+
+impl Sample {
+    fn attributes(&self, key: &'static str, value: impl Into<Value>) -> Vec<KeyValue> {
+        let mut attributes = self.attributes.clone();
+        attributes.push(KeyValue::new(key, value));
+        attributes
+    }
+
+    fn add(&mut self, other: Self) {
+        for (state, n) in other.states {
+            *self.states.entry(state).or_default() += n;
+        }
+        self.threads += other.threads;
+        self.rss += other.rss;
+        self.vsize += other.vsize;
+        self.user_ms += other.user_ms;
+        self.system_ms += other.system_ms;
+        // a partial sum would jump as the I/O of processes becomes readable or not
+        self.io = self
+            .io
+            .zip(other.io)
+            .map(|((read, written), (r, w))| (read + r, written + w));
+        self.cpu_percent = match (self.cpu_percent, other.cpu_percent) {
+            (Some(a), Some(b)) => Some(a + b),
+            (it, None) | (None, it) => it,
+        };
+    }
+
+    fn cpu_times(&self) -> [(u64, &'static str); 2] {
+        [(self.user_ms, "user"), (self.system_ms, "system")]
+    }
+
+    fn io_bytes(&self) -> Option<[(u64, &'static str); 2]> {
+        let (read, written) = self.io?;
+        Some([(read, "read_bytes"), (written, "write_bytes")])
+    }
+}
+
+/// Processes of the process group, read once per interval for all of its instruments.
+pub struct Processes {
+    group: Group,
+    cmdline: RegexSet,
+    uids: Vec<u32>,
+    top: usize,
+    top_by: ProcessOrder,
+    /// a series for each process, otherwise they are summed up by name
+    pub pid: bool,
+    handle: ProcessHandle,
+    system: System,
+    cached: Cached<Arc<Vec<Sample>>>,
+    // keyed by pid and start time as pids are reused
+    deltas: Deltas<(i32, u64)>,
+}
+
+impl Processes {
+    pub fn new(cfg: &ProcessGroupConfig, default_interval: Duration) -> Result<Self> {
+        let group = Group::new(&cfg.group(), default_interval)?;
+        Ok(Self {
+            cached: group.cached(),
+            group,
+            cmdline: cfg.cmdlines()?,
+            uids: cfg.uids.clone(),
+            top: cfg.top,
+            top_by: cfg.top_by,
+            pid: cfg.pid,
+            handle: ProcessHandle::new(),
+            system: System::default(),
+            deltas: Deltas::new(),
+        })
+    }
+
+    fn get(&self) -> Option<Arc<Vec<Sample>>> {
+        self.cached
+            .get(|| self.read().map(Arc::new))
+            .map(|(_, it)| it)
+    }
+
+    fn read(&self) -> Option<Vec<Sample>> {
+        let at = Instant::now();
+        let ticks = self.system.tick_per_sec;
+
+        let mut selected = vec![];
+        for process in self.handle.all(Some(self.group.interval)).ok()? {
+            // the process has exited
+            let Ok(stat) = process.stat() else {
+                continue;
+            };
+            if !self.group.allows_device(&stat.comm) {
+                continue;
+            }
+            if !self.uids.is_empty() && !process.uid().is_ok_and(|it| self.uids.contains(&it)) {
+                continue;
+            }
+            if !self.cmdline.is_empty()
+                && !process
+                    .cmdline()
+                    .is_ok_and(|it| self.cmdline.is_match(&it.join(" ")))
+            {
+                continue;
+            }
+            let key = (stat.pid, stat.starttime);
+            let delta = self.deltas.update(key, at, vec![stat.utime + stat.stime]);
+            selected.push((process, stat, delta));
+        }
+        let alive: HashSet<_> = selected
+            .iter()
+            .map(|(_, stat, _)| (stat.pid, stat.starttime))
+            .collect();
+        self.deltas.retain(|key| alive.contains(key));
+
+        if self.top > 0 {
+            match self.top_by {
+                // processes without a previous reading rank last, by their total CPU time
+                ProcessOrder::Cpu => selected.sort_by_key(|(_, stat, delta)| {
+                    Reverse(match delta {
+                        Some((d, _)) => (true, d[0]),
+                        None => (false, stat.utime + stat.stime),
+                    })
+                }),
+                ProcessOrder::Memory => selected.sort_by_key(|(_, stat, _)| Reverse(stat.rss)),
+            }
+            selected.truncate(self.top);
+        }
+
+        let samples = selected.into_iter().map(|(process, stat, delta)| {
+            let state = stat.state().map_or_else(
+                |_| stat.state.to_string(),
+                |it| format!("{:?}", it).to_lowercase(),
+            );
+            let mut attributes = vec![KeyValue::new("name", stat.comm.clone())];
+            if self.pid {
+                attributes.push(KeyValue::new("pid", i64::from(stat.pid)));
+            }
+            Sample {
+                attributes,
+                states: BTreeMap::from([(state, 1)]),
+                threads: u64::try_from(stat.num_threads).unwrap_or(0),
+                rss: stat.rss * self.system.page_size,
+                vsize: stat.vsize,
+                user_ms: stat.utime * 1000 / ticks,
+                system_ms: stat.stime * 1000 / ticks,
+                io: process.io().ok().map(|io| (io.read_bytes, io.write_bytes)),
+                cpu_percent: delta
+                    .filter(|(_, secs)| *secs > 0.0)
+                    .map(|(d, secs)| d[0] as f64 * 100.0 / ticks as f64 / secs),
+            }
+        });
+        if self.pid {
+            return Some(samples.collect());
+        }
+
+        let mut by_name = BTreeMap::<String, Sample>::new();
+        for sample in samples {
+            let name = sample.attributes[0].value.to_string();
+            match by_name.get_mut(&name) {
+                Some(it) => it.add(sample),
+                None => {
+                    by_name.insert(name, sample);
+                }
+            }
+        }
+        Some(by_name.into_values().collect())
+    }
+}
+
+impl super::super::Otlp {
+    pub fn process_gauges(&self, processes: &Arc<Processes>) -> Result<ObservableGauge<u64>> {
+        let processes = processes.clone();
+
+        let gauge = self
+            .meter
+            .u64_observable_gauge("ProcessStat")
+            .with_description(
+                "Resident (rss_bytes) and virtual (vsize_bytes) memory and threads (threads) \
+                 of the selected processes.",
+            )
+            .with_callback(move |gauge| {
+                let Some(samples) = processes.get() else {
+                    return;
+                };
+
+                for sample in samples.iter() {
+                    let gauges = [
+                        (sample.rss, "rss_bytes"),
+                        (sample.vsize, "vsize_bytes"),
+                        (sample.threads, "threads"),
+                    ];
+                    for (m, stat) in gauges {
+                        gauge.observe(m, &sample.attributes("stat", stat));
+                    }
+                }
+            })
+            .build();
+        Ok(gauge)
+    }
+
+    pub fn process_state_gauges(&self, processes: &Arc<Processes>) -> Result<ObservableGauge<u64>> {
+        let processes = processes.clone();
+
+        let gauge = self
+            .meter
+            .u64_observable_gauge("ProcessState")
+            .with_description("Number of the selected processes in each state.")
+            .with_callback(move |gauge| {
+                let Some(samples) = processes.get() else {
+                    return;
+                };
+
+                for sample in samples.iter() {
+                    for (state, n) in &sample.states {
+                        gauge.observe(*n, &sample.attributes("state", state.clone()));
+                    }
+                }
+            })
+            .build();
+        Ok(gauge)
+    }
+
+    /// Only with `pid`, the sums by name are not monotonic as the processes start, exit
+    /// or leave the top N.
+    pub fn process_cpu_time_counters(
+        &self,
+        processes: &Arc<Processes>,
+    ) -> Result<ObservableCounter<u64>> {
+        let processes = processes.clone();
+
+        let counter = self
+            .meter
+            .u64_observable_counter("ProcessCpuTime")
+            .with_unit("ms")
+            .with_description("Time each selected process has spent in user and system mode.")
+            .with_callback(move |counter| {
+                let Some(samples) = processes.get() else {
+                    return;
+                };
+
+                for sample in samples.iter() {
+                    for (m, stat) in sample.cpu_times() {
+                        counter.observe(m, &sample.attributes("stat", stat));
+                    }
+                }
+            })
+            .build();
+        Ok(counter)
+    }
+
+    /// Only without `pid`, named apart from the counters as the sums by name can drop.
+    pub fn process_cpu_time_gauges(
+        &self,
+        processes: &Arc<Processes>,
+    ) -> Result<ObservableGauge<u64>> {
+        let processes = processes.clone();
+
+        let gauge = self
+            .meter
+            .u64_observable_gauge("ProcessCpuTimeByName")
+            .with_unit("ms")
+            .with_description(
+                "Time the selected processes with each name have spent in user and system mode.",
+            )
+            .with_callback(move |gauge| {
+                let Some(samples) = processes.get() else {
+                    return;
+                };
+
+                for sample in samples.iter() {
+                    for (m, stat) in sample.cpu_times() {
+                        gauge.observe(m, &sample.attributes("stat", stat));
+                    }
+                }
+            })
+            .build();
+        Ok(gauge)
+    }
+
+    /// Only with `pid`, as [`Self::process_cpu_time_counters`].
+    pub fn process_io_counters(
+        &self,
+        processes: &Arc<Processes>,
+    ) -> Result<ObservableCounter<u64>> {
+        let processes = processes.clone();
+
+        let counter = self
+            .meter
+            .u64_observable_counter("ProcessIo")
+            .with_unit("By")
+            .with_description("Bytes each selected process has read from and written to storage.")
+            .with_callback(move |counter| {
+                let Some(samples) = processes.get() else {
+                    return;
+                };
+
+                for sample in samples.iter() {
+                    for (m, stat) in sample.io_bytes().into_iter().flatten() {
+                        counter.observe(m, &sample.attributes("stat", stat));
+                    }
+                }
+            })
+            .build();
+        Ok(counter)
+    }
+
+    /// Only without `pid`, as [`Self::process_cpu_time_gauges`].
+    pub fn process_io_gauges(&self, processes: &Arc<Processes>) -> Result<ObservableGauge<u64>> {
+        let processes = processes.clone();
+
+        let gauge = self
+            .meter
+            .u64_observable_gauge("ProcessIoByName")
+            .with_unit("By")
+            .with_description(
+                "Bytes the selected processes with each name have read from and written to storage.",
+            )
+            .with_callback(move |gauge| {
+                let Some(samples) = processes.get() else {
+                    return;
+                };
+
+                for sample in samples.iter() {
+                    for (m, stat) in sample.io_bytes().into_iter().flatten() {
+                        gauge.observe(m, &sample.attributes("stat", stat));
+                    }
+                }
+            })
+            .build();
+        Ok(gauge)
+    }
+
+    pub fn process_cpu_utilization_gauges(
+        &self,
+        processes: &Arc<Processes>,
+    ) -> Result<ObservableGauge<f64>> {
+        let processes = processes.clone();
+
+        let gauge = self
+            .meter
+            .f64_observable_gauge("ProcessCpuUtilization")
+            .with_unit("%")
+            .with_description(
+                "Percentage of one CPU each selected process has used since the last observation.",
+            )
+            .with_callback(move |gauge| {
+                let Some(samples) = processes.get() else {
+                    return;
+                };
+
+                for sample in samples.iter() {
+                    if let Some(m) = sample.cpu_percent {
+                        gauge.observe(m, &sample.attributes);
+                    }
+                }
+            })
+            .build();
+        Ok(gauge)
+    }
+}
@@ -0,0 +1,114 @@
+// Copyright (c) 2023-2024 Optimatist Technology Co., Ltd. All rights reserved.
+// DO NOT ALTER OR REMOVE COPYRIGHT NOTICES OR THIS FILE HEADER.
+//
+// This file is part of PSH.
+//
+// PSH is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License
+// as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+//
+// PSH is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even
+// the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License along with Performance Savior Home (PSH). If not,
+// see <https://www.gnu.org/licenses/>.
+
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    hash::Hash,
+    sync::Mutex,
+    time::Instant,
+};
+
+struct Last {
+    at: Instant,
+    values: Vec<u64>,
+    deltas: Option<(Vec<u64>, f64)>,
+}
+
+/// Increments of cumulative counters between readings, keyed by device.
+pub struct Deltas<K> {
+    last: Mutex<HashMap<K, Last>>,
+}
+
+impl<K: Eq + Hash> Deltas<K> {
+    pub fn new() -> Self {
+        Self {
+            last: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Increments since the previous reading and the seconds in between, `None` for the first one.
+    /// The same reading taken `at` the same time keeps the increments, a counter going backwards (reset) has none.
+    pub fn update(&self, key: K, at: Instant, values: Vec<u64>) -> Option<(Vec<u64>, f64)> {
+        let mut guard = self.last.lock().unwrap();
+        let last = match guard.entry(key) {
+            Entry::Vacant(it) => {
+                it.insert(Last {
+                    at,
+                    values,
+                    deltas: None,
+                });
+                return None;
+            }
+            Entry::Occupied(it) => it.into_mut(),
+        };
+        if at > last.at {
+            let deltas = values
+                .iter()
+                .zip(&last.values)
+                .map(|(new, old)| new.saturating_sub(*old))
+                .collect();
+            let elapsed = at - last.at;
+            *last = Last {
+                at,
+                values,
+                deltas: Some((deltas, elapsed.as_secs_f64())),
+            };
+        }
+        let deltas = last.deltas.clone();
+        drop(guard);
+        deltas
+    }
+
+    /// Forget the keys no longer read, such as exited processes.
+    pub fn retain(&self, keep: impl Fn(&K) -> bool) {
+        self.last.lock().unwrap().retain(|key, _| keep(key));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_deltas() {
+        let deltas = Deltas::new();
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        assert_eq!(deltas.update("sda", at(0), vec![10, 100]), None);
+        assert_eq!(
+            deltas.update("sda", at(2), vec![16, 150]),
+            Some((vec![6, 50], 2.0))
+        );
+        // another instrument reading the same sample gets the same increments
+        assert_eq!(
+            deltas.update("sda", at(2), vec![16, 150]),
+            Some((vec![6, 50], 2.0))
+        );
+        // a reset counter has no increment
+        assert_eq!(
+            deltas.update("sda", at(3), vec![4, 170]),
+            Some((vec![0, 20], 1.0))
+        );
+        assert_eq!(
+            deltas.update("sda", at(4), vec![9, 170]),
+            Some((vec![5, 0], 1.0))
+        );
+
+        deltas.retain(|key| *key != "sda");
+        assert_eq!(deltas.update("sda", at(5), vec![9, 170]), None);
+    }
+}
@@ -15,9 +15,12 @@
 use opentelemetry::{metrics::ObservableGauge, Array, KeyValue, Value};
 use psh_system::rps::RpsHandle;
 
+use super::Group;
+
 impl super::super::Otlp {
-    pub fn rps_gauges(&self) -> anyhow::Result<ObservableGauge<u64>> {
-        let token = self.token.clone();
+    pub fn rps_gauges(&self, group: &Group) -> anyhow::Result<ObservableGauge<u64>> {
+        let cached = group.cached();
+        let group = group.clone();
         let rps = RpsHandle::new();
 
         let gauge = self
@@ -25,10 +28,13 @@ impl super::super::Otlp {
             .u64_observable_gauge("RpsStat")
             .with_description("System profile rps statistics.")
             .with_callback(move |gauge| {
-                let Ok(rps_details) = rps.info() else {
+                let Some((_, rps_details)) = cached.get(|| rps.info().ok()) else {
                     return;
                 };
-                for detail in rps_details {
+                for detail in rps_details
+                    .into_iter()
+                    .filter(|it| group.allows_device(&it.dev))
+                {
                     let dev = detail.dev;
                     for rps_queue in detail.queues {
                         let gauges = [(
@@ -43,7 +49,7 @@ impl super::super::Otlp {
                             ],
                         )];
                         gauges.into_iter().for_each(|(m, [kv1, kv2, kv3])| {
-                            let a = &[KeyValue::new("token", token.clone()), kv1, kv2, kv3];
+                            let a = &[kv1, kv2, kv3];
                             gauge.observe(m, a);
                         });
                     }
@@ -16,10 +16,13 @@ use opentelemetry::metrics::ObservableGauge;
 use opentelemetry::KeyValue;
 use psh_system::vmstat::VmstatHandle;
 
+use super::Group;
+
 impl super::super::Otlp {
-    pub fn vmstat_gauges(&self) -> anyhow::Result<ObservableGauge<u64>> {
-        let token = self.token.clone();
-        let interval = self.interval;
+    pub fn vmstat_gauges(&self, group: &Group) -> anyhow::Result<ObservableGauge<u64>> {
+        let interval = group.interval;
+        let cached = group.cached();
+        let group = group.clone();
         let vmstat = VmstatHandle::new();
 
         let gauge = self
@@ -27,18 +30,12 @@ impl super::super::Otlp {
             .u64_observable_gauge("VmStat")
             .with_description("System profile vmstat statistics.")
             .with_callback(move |gauge| {
-                let Ok(stat) = vmstat.stat(Some(interval)) else {
+                let Some((_, stat)) = cached.get(|| vmstat.stat(Some(interval)).ok()) else {
                     return;
                 };
 
-                for (k, v) in stat {
-                    gauge.observe(
-                        v as u64,
-                        &[
-                            KeyValue::new("stat", k),
-                            KeyValue::new("token", token.clone()),
-                        ],
-                    )
+                for (k, v) in stat.into_iter().filter(|(k, _)| group.allows_field(k)) {
+                    gauge.observe(v as u64, &[KeyValue::new("stat", k)])
                 }
             })
             .build();
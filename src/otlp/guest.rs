@@ -0,0 +1,154 @@
+// Copyright (c) 2023-2024 Optimatist Technology Co., Ltd. All rights reserved.
+// DO NOT ALTER OR REMOVE COPYRIGHT NOTICES OR THIS FILE HEADER.
+//
+// This file is part of PSH.
+//
+// PSH is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License
+// as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+//
+// PSH is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even
+// the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License along with Performance Savior Home (PSH). If not,
+// see <https://www.gnu.org/licenses/>.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use opentelemetry::{
+    metrics::{Counter, Gauge, Meter},
+    KeyValue,
+};
+
+use crate::config::name_matches;
+
+#[derive(Clone)]
+enum Instrument {
+    Gauge(Gauge<f64>),
+    Counter(Counter<f64>),
+}
+
+/// Records the metrics exported by guests, keyed by their names.
+pub struct GuestMetrics {
+    meter: Meter,
+    /// names of the metrics holding increments, `*` suffix matches a prefix
+    counters: Vec<String>,
+    /// distinct metrics each task can record
+    max_metrics: usize,
+    /// distinct metrics all tasks can record, instruments are never removed
+    max_total_metrics: usize,
+    instruments: Mutex<HashMap<String, Instrument>>,
+    /// whether a metric beyond `max_total_metrics` has been dropped
+    capped: AtomicBool,
+}
+
+impl GuestMetrics {
+    pub fn new(
+        meter: Meter,
+        counters: Vec<String>,
+        max_metrics: usize,
+        max_total_metrics: usize,
+    ) -> Self {
+        Self {
+            meter,
+            counters,
+            max_metrics,
+            max_total_metrics,
+            instruments: Mutex::new(HashMap::new()),
+            capped: AtomicBool::new(false),
+        }
+    }
+
+    fn is_counter(&self, name: &str) -> bool {
+        self.counters
+            .iter()
+            .any(|pattern| name_matches(pattern, name))
+    }
+
+    fn instrument(&self, name: &str) -> Option<Instrument> {
+        let mut instruments = self.instruments.lock().unwrap();
+        if let Some(instrument) = instruments.get(name) {
+            return Some(instrument.clone());
+        }
+        if instruments.len() >= self.max_total_metrics {
+            if !self.capped.swap(true, Ordering::Relaxed) {
+                tracing::warn!(
+                    "Not recording guest metric {}, tasks can record at most {}",
+                    name,
+                    self.max_total_metrics
+                );
+            }
+            return None;
+        }
+        let instrument = if self.is_counter(name) {
+            Instrument::Counter(self.meter.f64_counter(name.to_owned()).build())
+        } else {
+            Instrument::Gauge(self.meter.f64_gauge(name.to_owned()).build())
+        };
+        instruments.insert(name.to_owned(), instrument.clone());
+        drop(instruments);
+        Some(instrument)
+    }
+
+    /// Record the value as the current value of a gauge, or an increment of a counter.
+    ///
+    /// The `task_id` tag is left out, every task would otherwise add series that are never removed.
+    fn record(&self, name: &str, value: f64, tags: &[(String, String)]) {
+        let Some(instrument) = self.instrument(name) else {
+            return;
+        };
+        let attributes: Vec<_> = tags
+            .iter()
+            .filter(|(k, _)| k != "task_id")
+            .map(|(k, v)| KeyValue::new(k.clone(), v.clone()))
+            .collect();
+        match instrument {
+            Instrument::Gauge(gauge) => gauge.record(value, &attributes),
+            Instrument::Counter(counter) => counter.add(value, &attributes),
+        }
+    }
+}
+
+/// The guest metrics recorded by a task, at most `max_metrics` distinct ones.
+#[derive(Clone)]
+pub struct TaskMetrics {
+    metrics: Arc<GuestMetrics>,
+    names: HashSet<String>,
+    /// whether a metric beyond `max_metrics` has been dropped
+    capped: bool,
+}
+
+impl TaskMetrics {
+    pub fn new(metrics: Arc<GuestMetrics>) -> Self {
+        Self {
+            metrics,
+            names: HashSet::new(),
+            capped: false,
+        }
+    }
+
+    /// Record a metric, the ones beyond `max_metrics` are dropped with a single warning,
+    /// the data is still sent to the other sinks.
+    pub fn record(&mut self, name: &str, value: f64, tags: &[(String, String)]) {
+        if !self.names.contains(name) {
+            if self.names.len() >= self.metrics.max_metrics {
+                if !self.capped {
+                    self.capped = true;
+                    tracing::warn!(
+                        "Not recording guest metric {}, a task can record at most {}",
+                        name,
+                        self.metrics.max_metrics
+                    );
+                }
+                return;
+            }
+            self.names.insert(name.to_owned());
+        }
+        self.metrics.record(name, value, tags);
+    }
+}
@@ -12,22 +12,34 @@
 // You should have received a copy of the GNU Lesser General Public License along with Performance Savior Home (PSH). If not,
 // see <https://www.gnu.org/licenses/>.
 
+mod exporter;
 pub mod gauges;
+mod guest;
+mod prometheus;
 
-use std::{sync::LazyLock, time::Duration};
+pub use guest::{GuestMetrics, TaskMetrics};
+pub use prometheus::PrometheusRegistry;
+
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, LazyLock},
+    time::Duration,
+};
 
 use anyhow::Result;
+use gauges::{cpu, disk, network, process::Processes, Group};
 use opentelemetry::{
     metrics::{Meter, MeterProvider},
     KeyValue,
 };
-use opentelemetry_otlp::{ExportConfig, MetricExporter, WithExportConfig, WithTonicConfig};
 use opentelemetry_sdk::{
     metrics::{PeriodicReader, SdkMeterProvider},
     runtime, Resource,
 };
+use psh_system::os::OsHandle;
 use tinyufo::TinyUfo;
-use tonic::{metadata::MetadataMap, transport::ClientTlsConfig};
+
+use crate::config::OtlpConfig;
 
 // TODO: Make size configurable
 static NET_DEV_SPEED: LazyLock<TinyUfo<String, Option<u32>>> =
@@ -35,25 +47,42 @@ static NET_DEV_SPEED: LazyLock<TinyUfo<String, Option<u32>>> =
 
 #[derive(Debug, Clone)]
 pub struct Otlp {
-    token: String,
     interval: Duration,
     meter: Meter,
     // NOTE: the field avoid provider early drop see: <https://github.com/open-telemetry/opentelemetry-rust/issues/1661>
-    _provider: SdkMeterProvider,
+    provider: SdkMeterProvider,
 }
 
 impl Otlp {
-    pub fn new(token: String, interval: Duration, export_config: ExportConfig) -> Result<Self> {
-        let provider = meter_provider(export_config, token.clone(), interval)?;
+    /// Metrics are pushed to the collector of `export` and collected by `prometheus` on each scrape.
+    /// The token is only sent in the authorization header.
+    pub fn new(
+        token: &str,
+        interval: Duration,
+        export: Option<&OtlpConfig>,
+        prometheus: Option<&PrometheusRegistry>,
+        resource: Resource,
+    ) -> Result<Self> {
+        let provider = meter_provider(export, prometheus, token, interval, resource)?;
         let meter = provider.meter("SystemProfile");
         Ok(Self {
-            token,
             interval,
             meter,
-            _provider: provider,
+            provider,
         })
     }
 
+    /// Metrics exported by guests, recorded alongside the system metrics.
+    pub fn guest_metrics(
+        &self,
+        counters: Vec<String>,
+        max_metrics: usize,
+        max_total_metrics: usize,
+    ) -> GuestMetrics {
+        let meter = self.provider.meter("GuestMetrics");
+        GuestMetrics::new(meter, counters, max_metrics, max_total_metrics)
+    }
+
     pub fn net_dev_speed(name: &String) -> Option<u32> {
         if let Some(speed) = NET_DEV_SPEED.get(name) {
             return speed;
@@ -65,59 +94,171 @@ impl Otlp {
         speed
     }
 
-    pub async fn otlp_tasks(&self) -> anyhow::Result<()> {
+    /// Register the enabled groups of system metrics.
+    pub async fn otlp_tasks(&self, cfg: &OtlpConfig) -> anyhow::Result<()> {
         let interval = self.interval;
+        let groups = &cfg.groups;
+        let group = |cfg| Group::new(cfg, interval);
 
-        if let Err(e) = self.mem_gauges() {
-            tracing::error!("Otlp memory: {e}")
+        if groups.memory.enable {
+            if let Err(e) = group(&groups.memory).and_then(|it| self.mem_gauges(&it)) {
+                tracing::error!("Otlp memory: {e}")
+            }
         }
-        if let Err(e) = self.net_gauges() {
-            tracing::error!("Otlp network: {e}")
+        if groups.network.enable {
+            let res = group(&groups.network).and_then(|it| {
+                let it = network::source(it);
+                self.net_gauges(&it)?;
+                self.net_counters(&it)?;
+                if cfg.derived_metrics {
+                    self.net_rate_gauges(&it)?;
+                }
+                Ok(())
+            });
+            if let Err(e) = res {
+                tracing::error!("Otlp network: {e}")
+            }
         }
-        if let Err(e) = self.disk_gagues() {
-            tracing::error!("Otlp disk: {e}")
+        if groups.disk.enable {
+            let res = group(&groups.disk).and_then(|it| {
+                let it = disk::source(it);
+                self.disk_gagues(&it)?;
+                self.disk_counters(&it)?;
+                if cfg.derived_metrics {
+                    self.disk_rate_gauges(&it)?;
+                }
+                Ok(())
+            });
+            if let Err(e) = res {
+                tracing::error!("Otlp disk: {e}")
+            }
         }
-        if let Err(e) = self.irq_gauges() {
-            tracing::error!("Otlp interrupt: {e}")
+        if groups.interrupt.enable {
+            if let Err(e) = group(&groups.interrupt).and_then(|it| self.irq_counters(&it)) {
+                tracing::error!("Otlp interrupt: {e}")
+            }
         }
-        if let Err(e) = self.cpu_gauges() {
-            tracing::error!("Otlp cpu: {e}")
+        if groups.cpu.enable {
+            let res = group(&groups.cpu).and_then(|it| {
+                let it = cpu::source(it);
+                self.cpu_gauges(&it)?;
+                self.cpu_time_counters(&it)?;
+                self.cpu_activity_counters(&it)?;
+                if cfg.derived_metrics {
+                    self.cpu_utilization_gauges(&it)?;
+                }
+                Ok(())
+            });
+            if let Err(e) = res {
+                tracing::error!("Otlp cpu: {e}")
+            }
         }
-        if let Err(e) = self.rps_gauges() {
-            tracing::error!("Otlp rps: {e}")
+        if groups.rps.enable {
+            if let Err(e) = group(&groups.rps).and_then(|it| self.rps_gauges(&it)) {
+                tracing::error!("Otlp rps: {e}")
+            }
         }
-        if let Err(e) = self.vmstat_gauges() {
-            tracing::error!("Otlp vmstat: {e}")
+        if groups.vmstat.enable {
+            if let Err(e) = group(&groups.vmstat).and_then(|it| self.vmstat_gauges(&it)) {
+                tracing::error!("Otlp vmstat: {e}")
+            }
+        }
+        if groups.process.enable {
+            let res = Processes::new(&groups.process, interval).and_then(|it| {
+                let it = Arc::new(it);
+                self.process_gauges(&it)?;
+                self.process_state_gauges(&it)?;
+                if it.pid {
+                    self.process_cpu_time_counters(&it)?;
+                    self.process_io_counters(&it)?;
+                } else {
+                    self.process_cpu_time_gauges(&it)?;
+                    self.process_io_gauges(&it)?;
+                }
+                if cfg.derived_metrics {
+                    self.process_cpu_utilization_gauges(&it)?;
+                }
+                Ok(())
+            });
+            if let Err(e) = res {
+                tracing::error!("Otlp process: {e}")
+            }
         }
 
         loop {
             tokio::time::sleep(interval).await;
         }
     }
+
+    /// Export the pending metrics and stop the periodic reader.
+    pub async fn shutdown(&self) -> Result<()> {
+        let provider = self.provider.clone();
+        // `shutdown` blocks until the reader task running on this runtime is done
+        tokio::task::spawn_blocking(move || provider.shutdown()).await??;
+        Ok(())
+    }
+}
+
+/// Identity of this host, `labels` are added last and override the detected attributes.
+pub fn resource(instance_id: Option<String>, labels: &BTreeMap<String, String>) -> Resource {
+    // values of the `host.arch` semantic convention
+    let arch = match std::env::consts::ARCH {
+        "x86_64" => "amd64",
+        "aarch64" => "arm64",
+        "arm" => "arm32",
+        "powerpc" => "ppc32",
+        "powerpc64" => "ppc64",
+        arch => arch,
+    };
+    let mut attributes = vec![
+        KeyValue::new("service.name", "PSH"),
+        KeyValue::new("service.version", env!("CARGO_PKG_VERSION")),
+        KeyValue::new("host.arch", arch),
+        KeyValue::new("os.type", std::env::consts::OS),
+    ];
+    if let Ok(hostname) = nix::unistd::gethostname() {
+        attributes.push(KeyValue::new(
+            "host.name",
+            hostname.to_string_lossy().into_owned(),
+        ));
+    }
+    if let Ok(os) = OsHandle::new().info() {
+        attributes.push(KeyValue::new("os.version", os.kernel.to_string()));
+        attributes.push(KeyValue::new(
+            "os.description",
+            os.distro.distro.to_string(),
+        ));
+    }
+    if let Some(instance_id) = instance_id {
+        attributes.push(KeyValue::new("service.instance.id", instance_id));
+    }
+    attributes.extend(
+        labels
+            .iter()
+            .map(|(k, v)| KeyValue::new(k.clone(), v.clone())),
+    );
+    Resource::new(attributes)
 }
 
 fn meter_provider(
-    export_config: ExportConfig,
-    token: String,
+    export: Option<&OtlpConfig>,
+    prometheus: Option<&PrometheusRegistry>,
+    token: &str,
     interval: Duration,
+    resource: Resource,
 ) -> Result<SdkMeterProvider> {
-    let mut meta = MetadataMap::new();
-    meta.insert("authorization", format!("Bearer {}", token).parse()?);
-    let otlp_exporter = MetricExporter::builder()
-        .with_tonic()
-        .with_tls_config(ClientTlsConfig::new().with_native_roots())
-        .with_metadata(meta)
-        .with_timeout(Duration::from_secs(10))
-        .with_export_config(export_config)
-        .build()?;
-    let reader = PeriodicReader::builder(otlp_exporter, runtime::Tokio)
-        .with_interval(interval)
-        .build();
-
-    let a = SdkMeterProvider::builder()
-        .with_reader(reader)
-        .with_resource(Resource::new(vec![KeyValue::new("service.name", "PSH")]))
-        .build();
-
-    Ok(a)
+    let mut builder = SdkMeterProvider::builder().with_resource(resource);
+
+    if let Some(export) = export {
+        let otlp_exporter = exporter::metric_exporter(export, token)?;
+        let reader = PeriodicReader::builder(otlp_exporter, runtime::Tokio)
+            .with_interval(interval)
+            .build();
+        builder = builder.with_reader(reader);
+    }
+    if let Some(prometheus) = prometheus {
+        builder = builder.with_reader(prometheus.reader()?);
+    }
+
+    Ok(builder.build())
 }
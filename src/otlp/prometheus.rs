@@ -0,0 +1,62 @@
+// Copyright (c) 2023-2024 Optimatist Technology Co., Ltd. All rights reserved.
+// DO NOT ALTER OR REMOVE COPYRIGHT NOTICES OR THIS FILE HEADER.
+//
+// This file is part of PSH.
+//
+// PSH is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License
+// as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+//
+// PSH is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even
+// the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License along with Performance Savior Home (PSH). If not,
+// see <https://www.gnu.org/licenses/>.
+
+use anyhow::Result;
+use opentelemetry_prometheus::PrometheusExporter;
+use prometheus::{Registry, TextEncoder};
+
+/// Registry collecting the metrics of the meter provider on each scrape.
+#[derive(Clone, Default)]
+pub struct PrometheusRegistry(Registry);
+
+impl PrometheusRegistry {
+    /// Reader to register with the meter provider, its metrics are collected by `render`.
+    pub fn reader(&self) -> Result<PrometheusExporter> {
+        let exporter = opentelemetry_prometheus::exporter()
+            .with_registry(self.0.clone())
+            // keep the names of the otlp metrics, units are in the help text
+            .without_units()
+            .without_scope_info()
+            .build()?;
+        Ok(exporter)
+    }
+
+    /// Collect the current metrics in the Prometheus text format.
+    pub fn render(&self) -> Result<String> {
+        let mut out = String::new();
+        TextEncoder::new().encode_utf8(&self.0.gather(), &mut out)?;
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use opentelemetry::{metrics::MeterProvider, KeyValue};
+    use opentelemetry_sdk::metrics::SdkMeterProvider;
+
+    use super::*;
+
+    #[test]
+    fn test_label_escaping() {
+        let registry = PrometheusRegistry::default();
+        let provider = SdkMeterProvider::builder()
+            .with_reader(registry.reader().unwrap())
+            .build();
+        let gauge = provider.meter("test").u64_gauge("escaped").build();
+        gauge.record(1, &[KeyValue::new("value", "a\\b\"c\nd")]);
+
+        let out = registry.render().unwrap();
+        assert!(out.contains(r#"escaped{value="a\\b\"c\nd"} 1"#), "{out}");
+    }
+}
@@ -12,14 +12,17 @@
 // You should have received a copy of the GNU Lesser General Public License along with Performance Savior Home (PSH). If not,
 // see <https://www.gnu.org/licenses/>.
 
+use std::sync::{atomic::AtomicBool, Arc};
+
 use anyhow::Context;
 use host_op_perf::PerfCtx;
 use host_op_system::SysCtx;
 use wasmtime::component::{Linker, ResourceTable};
-use wasmtime::{Config, Engine, Store};
+use wasmtime::{Config, Engine, Store, StoreLimitsBuilder};
 use wasmtime_wasi::{DirPerms, FilePerms, StdinStream, StdoutStream, WasiCtxBuilder};
 
-use super::{data_export, DataExportCtx, PshEngine, PshState};
+use super::{data_export, env, ComponentCache, DataExportCtx, PshEngine, PshState};
+use crate::config::{DirPerm, EnvConfig, FilePerm, LimitsConfig, PreopenConfig};
 
 #[allow(dead_code)]
 pub struct PshEngineBuilder {
@@ -28,6 +31,11 @@ pub struct PshEngineBuilder {
     use_perf_op: bool,
     use_system_op: bool,
     data_export_ctx: Option<DataExportCtx>,
+    limits: LimitsConfig,
+    cache: Option<Arc<ComponentCache>>,
+    allow_precompiled: bool,
+    preopens: Vec<(String, String, DirPerms, FilePerms)>,
+    interrupted: Arc<AtomicBool>,
 }
 
 #[allow(dead_code)]
@@ -42,6 +50,11 @@ impl PshEngineBuilder {
             use_perf_op: false,
             use_system_op: false,
             data_export_ctx: None,
+            limits: LimitsConfig::default(),
+            cache: None,
+            allow_precompiled: false,
+            preopens: vec![],
+            interrupted: Arc::default(),
         }
     }
 
@@ -63,23 +76,40 @@ impl PshEngineBuilder {
                 .context("Failed to link data-export module")?;
         }
 
-        self.wasi_ctx_builder
-            .preopened_dir("/", "/", DirPerms::READ, FilePerms::READ)?;
+        for (host, guest, dir_perms, file_perms) in self.preopens {
+            self.wasi_ctx_builder
+                .preopened_dir(&host, guest, dir_perms, file_perms)
+                .with_context(|| format!("Failed to preopen {host}"))?;
+        }
 
+        let limits = StoreLimitsBuilder::new()
+            .memory_size(self.limits.max_memory)
+            .instances(self.limits.max_instances)
+            .tables(self.limits.max_tables)
+            .table_elements(self.limits.max_table_elements)
+            .build();
         let state = PshState {
             name: "PSH Wasi Runtime".to_owned(),
             table: ResourceTable::new(),
             wasi_ctx: self.wasi_ctx_builder.build(),
-            perf_ctx: PerfCtx::new(),
-            sys_ctx: SysCtx::default(),
+            perf_ctx: PerfCtx::with_max_resources(self.limits.max_host_resources),
+            sys_ctx: SysCtx::with_max_resources(self.limits.max_host_resources),
             data_export_ctx: self.data_export_ctx.unwrap_or(DataExportCtx { ctx: None }),
+            limits,
         };
-        let store = Store::new(&engine, state);
+        let mut store = Store::new(&engine, state);
+        store.limiter(|state| &mut state.limits);
+        if self.limits.fuel > 0 {
+            store.set_fuel(self.limits.fuel)?;
+        }
 
         Ok(PshEngine {
             engine,
             store,
             linker,
+            interrupted: self.interrupted,
+            cache: self.cache,
+            allow_precompiled: self.allow_precompiled,
         })
     }
 
@@ -127,6 +157,29 @@ impl PshEngineBuilder {
         self.wasi_ctx_builder.env(k, v);
         self
     }
+
+    /// Pass host variables allowed by `cfg`, names reserved by psh are never passed.
+    pub fn inherit_envs(mut self, cfg: &EnvConfig) -> Self {
+        for (k, v) in std::env::vars() {
+            if cfg.allows(&k) && !env::is_reserved(&k) {
+                self.wasi_ctx_builder.env(k, v);
+            }
+        }
+        self
+    }
+
+    /// Variables supplied with a task, names reserved by psh are ignored.
+    pub fn task_envs(mut self, envs: &[(String, String)]) -> Self {
+        for (k, v) in envs {
+            if env::is_reserved(k) {
+                tracing::warn!("Ignored reserved environment variable {k} of the task");
+                continue;
+            }
+            self.wasi_ctx_builder.env(k, v);
+        }
+        self
+    }
+
     pub fn wasi_args(mut self, args: &[impl AsRef<str>]) -> Self {
         self.wasi_ctx_builder.args(args);
         self
@@ -137,6 +190,38 @@ impl PshEngineBuilder {
         self
     }
 
+    pub fn wasi_preopened_dir(
+        mut self,
+        host: impl Into<String>,
+        guest: impl Into<String>,
+        dir_perms: DirPerms,
+        file_perms: FilePerms,
+    ) -> Self {
+        self.preopens
+            .push((host.into(), guest.into(), dir_perms, file_perms));
+        self
+    }
+
+    pub fn preopens(mut self, preopens: &[PreopenConfig]) -> Self {
+        for it in preopens {
+            let dir_perms = it.dir_perms.iter().fold(DirPerms::empty(), |acc, p| {
+                acc | match p {
+                    DirPerm::Read => DirPerms::READ,
+                    DirPerm::Mutate => DirPerms::MUTATE,
+                }
+            });
+            let file_perms = it.file_perms.iter().fold(FilePerms::empty(), |acc, p| {
+                acc | match p {
+                    FilePerm::Read => FilePerms::READ,
+                    FilePerm::Write => FilePerms::WRITE,
+                }
+            });
+            let guest = it.guest.as_ref().unwrap_or(&it.host);
+            self = self.wasi_preopened_dir(&it.host, guest, dir_perms, file_perms);
+        }
+        self
+    }
+
     pub fn wasi_inherit_network(mut self) -> Self {
         self.wasi_ctx_builder.inherit_network();
         self
@@ -171,4 +256,29 @@ impl PshEngineBuilder {
         self.data_export_ctx = ctx;
         self
     }
+
+    pub fn component_cache(mut self, cache: Option<Arc<ComponentCache>>) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    /// Accept components precompiled by `psh compile`.
+    /// Deserializing a precompiled component is unsafe, only enable this for trusted sources.
+    pub const fn allow_precompiled(mut self, enable: bool) -> Self {
+        self.allow_precompiled = enable;
+        self
+    }
+
+    /// Flag set by the `Interrupter` of the engine, for host functions that block to give up.
+    pub fn interrupted(mut self, interrupted: Arc<AtomicBool>) -> Self {
+        self.interrupted = interrupted;
+        self
+    }
+
+    /// Limit the memory, tables, fuel and host resources available to the guest.
+    pub fn limits(mut self, limits: LimitsConfig) -> Self {
+        self.engine_config.consume_fuel(limits.fuel > 0);
+        self.limits = limits;
+        self
+    }
 }
@@ -0,0 +1,156 @@
+// Copyright (c) 2023-2024 Optimatist Technology Co., Ltd. All rights reserved.
+// DO NOT ALTER OR REMOVE COPYRIGHT NOTICES OR THIS FILE HEADER.
+//
+// This file is part of PSH.
+//
+// PSH is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License
+// as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+//
+// PSH is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even
+// the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License along with Performance Savior Home (PSH). If not,
+// see <https://www.gnu.org/licenses/>.
+
+use std::fs::{self, File};
+use std::hash::{Hash, Hasher};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+
+use anyhow::{bail, Context, Result};
+use nix::unistd::geteuid;
+use sha2::{Digest, Sha256};
+use wasmtime::component::Component;
+use wasmtime::Engine;
+
+const EXTENSION: &str = "cwasm";
+
+/// On-disk cache of compiled components.
+///
+/// Entries are keyed by the hash of the WASM binary and the fingerprint of the
+/// engine which compiled it, so entries from another wasmtime version or engine
+/// config are never picked up.
+pub struct ComponentCache {
+    dir: PathBuf,
+    max_size: u64,
+    tmp_id: AtomicU64,
+}
+
+impl ComponentCache {
+    pub fn new(dir: impl Into<PathBuf>, max_size: u64) -> Self {
+        Self {
+            dir: dir.into(),
+            max_size,
+            tmp_id: AtomicU64::new(0),
+        }
+    }
+
+    /// Load a compiled component from the cache, compiling and caching it on a miss.
+    pub fn load(&self, engine: &Engine, binary: &[u8]) -> Result<Component> {
+        let path = self.path(engine, binary);
+        if let Err(e) = self.check_dir() {
+            tracing::warn!("Cache disabled: {:#}", e);
+            return Component::from_binary(engine, binary);
+        }
+
+        if path.exists() {
+            // SAFETY: `check_dir` ensures only we can write to the cache directory,
+            // and entries are only written by `store` from components compiled by us.
+            match unsafe { Component::deserialize_file(engine, &path) } {
+                Ok(component) => {
+                    // Update mtime so the entry is evicted in LRU order.
+                    let _ = File::open(&path).and_then(|f| f.set_modified(SystemTime::now()));
+                    return Ok(component);
+                }
+                Err(e) => tracing::warn!("Ignore invalid cache {}: {}", path.display(), e),
+            }
+        }
+
+        let component = Component::from_binary(engine, binary)?;
+        if let Err(e) = self.store(&path, &component) {
+            tracing::warn!("Failed to cache component {}: {:#}", path.display(), e);
+        }
+        Ok(component)
+    }
+
+    fn path(&self, engine: &Engine, binary: &[u8]) -> PathBuf {
+        let wasm_hash = hex::encode(Sha256::digest(binary));
+        // Unlike `DefaultHasher`, sha256 is stable across Rust versions.
+        let mut hasher = Sha256Hasher(Sha256::new());
+        engine.precompile_compatibility_hash().hash(&mut hasher);
+        let fingerprint = hex::encode(&hasher.0.finalize()[..8]);
+        self.dir
+            .join(format!("{}-{}.{}", wasm_hash, fingerprint, EXTENSION))
+    }
+
+    /// Create the cache directory, and refuse it unless only we can write to it,
+    /// since loading an entry runs its code.
+    fn check_dir(&self) -> Result<()> {
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&self.dir)
+            .context("Failed to create cache dir")?;
+
+        let meta = fs::metadata(&self.dir)?;
+        if meta.uid() != geteuid().as_raw() {
+            bail!("{} is not owned by the current user", self.dir.display());
+        }
+        if meta.mode() & 0o022 != 0 {
+            bail!("{} is writable by group or others", self.dir.display());
+        }
+        Ok(())
+    }
+
+    fn store(&self, path: &PathBuf, component: &Component) -> Result<()> {
+        // Write to a temporary file first so readers never see a partial entry.
+        let tmp = self.dir.join(format!(
+            ".{}-{}.tmp",
+            std::process::id(),
+            self.tmp_id.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::write(&tmp, component.serialize()?)?;
+        fs::rename(&tmp, path)?;
+
+        self.evict()
+    }
+
+    /// Remove least recently used entries until the cache fits in `max_size`.
+    fn evict(&self) -> Result<()> {
+        let mut entries = vec![];
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|it| it != EXTENSION) {
+                continue;
+            }
+            let meta = fs::metadata(&path)?;
+            entries.push((meta.modified()?, meta.len(), path));
+        }
+
+        let mut size: u64 = entries.iter().map(|(_, len, _)| len).sum();
+        entries.sort();
+        for (_, len, path) in entries {
+            if size <= self.max_size {
+                break;
+            }
+            fs::remove_file(&path)?;
+            size -= len;
+        }
+        Ok(())
+    }
+}
+
+/// Feeds `Hash` implementations into sha256.
+struct Sha256Hasher(Sha256);
+
+impl Hasher for Sha256Hasher {
+    fn write(&mut self, bytes: &[u8]) {
+        self.0.update(bytes);
+    }
+
+    fn finish(&self) -> u64 {
+        unreachable!("only the digest is used")
+    }
+}
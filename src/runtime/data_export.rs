@@ -14,10 +14,11 @@
 
 use std::{
     sync::{
-        atomic::{AtomicUsize, Ordering},
-        Arc,
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Condvar, Mutex,
     },
     thread::{self, JoinHandle},
+    time::Duration,
 };
 
 use crossbeam::queue::SegQueue;
@@ -29,7 +30,10 @@ use prost::Message;
 use tokio::runtime::Runtime;
 use wasmtime::component::Linker;
 
-use crate::services::rpc::RpcClient;
+use super::file_sink::FileSink;
+use crate::config::{DataExportConfig, OverflowPolicy};
+use crate::otlp::TaskMetrics;
+use crate::services::{influxdb::InfluxClient, rpc::RpcClient, spool::Spool};
 use psh_proto::{Data, DataType, ExportDataReq};
 
 wasmtime::component::bindgen!({
@@ -56,56 +60,93 @@ impl FieldValue for WitFieldValue {
     }
 }
 
+/// Value recorded as an otlp metric, text and timestamps are not.
+const fn metric_value(value: &WitFieldValue) -> Option<f64> {
+    match value {
+        WitFieldValue::Float(x) => Some(*x),
+        WitFieldValue::Int(x) => Some(*x as f64),
+        WitFieldValue::Uint(x) => Some(*x as f64),
+        WitFieldValue::Boolean(b) => Some(*b as u8 as f64),
+        WitFieldValue::Text(_) | WitFieldValue::NsTs(_) => None,
+    }
+}
+
+/// How often a guest blocked on a full buffer checks whether its task was interrupted.
+const BLOCK_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
 pub struct DataExporter {
     bytes_len: Arc<AtomicUsize>,
+    bytes_capacity: usize,
     bytes_watermark: usize,
+    overflow: OverflowPolicy,
+    overflows: Arc<AtomicU64>,
+    /// Number of times the exporter thread has taken all the queued data, notified each time.
+    drained: Arc<(Mutex<u64>, Condvar)>,
     data_queue: Arc<SegQueue<Option<Data>>>,
-    task_id: String,
-    exporter: JoinHandle<()>,
+    exporter: Option<JoinHandle<()>>,
+    /// Nanoseconds in one unit of the timestamps taken by the sink.
+    precision: u64,
+    /// Set when the task is cancelled or psh shuts down, see `Interrupter`.
+    interrupted: Arc<AtomicBool>,
 }
 
 impl DataExporter {
     pub fn new(
-        bytes_capacity: usize,
-        bytes_watermark: usize,
+        cfg: &DataExportConfig,
         task_id: String,
-        rpc_client: RpcClient,
+        sink: Sink,
+        spool: Option<Arc<Spool>>,
+        overflows: Arc<AtomicU64>,
+        interrupted: Arc<AtomicBool>,
     ) -> Self {
-        // TODO: `bytes_capacity` is not used because we not have a static allocation
-        // for `Data`, maybe we will remove this in the future
-        let _ = bytes_capacity;
         let data_queue = Arc::new(SegQueue::<Option<Data>>::new());
         let bytes_len = Arc::new(AtomicUsize::new(0));
+        let drained = Arc::new((Mutex::new(0), Condvar::new()));
+        let precision = sink.precision();
 
         let exporter = thread::spawn({
             let data_queue = Arc::clone(&data_queue);
             let bytes_len = Arc::clone(&bytes_len);
-            let task_id = task_id.clone();
+            let drained = Arc::clone(&drained);
+            let max_batch_age = Duration::from_secs(cfg.max_batch_age);
+            let max_batch_size = cfg.max_batch_size;
             move || {
                 let rt = Runtime::new().expect("Failed to init exporter runtime");
+                let send = |data| {
+                    let merged = ExportDataReq {
+                        task_id: task_id.clone(),
+                        data,
+                    };
+                    export(&rt, &sink, spool.as_deref(), merged);
+                };
                 let mut data = Vec::new();
+                let mut batch_len = 0;
                 loop {
                     match data_queue.pop() {
                         Some(Some(o)) => {
+                            let len = o.encoded_len();
                             // No critical section, relaxed ordering is fine.
-                            bytes_len.fetch_sub(o.encoded_len(), Ordering::Relaxed);
+                            bytes_len.fetch_sub(len, Ordering::Relaxed);
+                            if !data.is_empty() && batch_len + len > max_batch_size {
+                                send(std::mem::take(&mut data));
+                                batch_len = 0;
+                            }
+                            batch_len += len;
                             data.push(o);
                         }
                         poped => {
+                            // Take the lock so a blocked producer can't miss the notification
+                            // between checking for room and waiting.
+                            *drained.0.lock().unwrap() += 1;
+                            drained.1.notify_all();
                             if !data.is_empty() {
-                                let merged = ExportDataReq {
-                                    task_id: task_id.clone(),
-                                    data: data.clone(),
-                                };
-
-                                let mut rpc_client = rpc_client.clone();
-                                rt.block_on(async move {
-                                    let _ = rpc_client.export_data(merged).await;
-                                });
-                                data.clear();
+                                send(std::mem::take(&mut data));
+                                batch_len = 0;
                             }
                             match poped {
-                                None => thread::park(),
+                                // Wake up periodically so data does not wait for the watermark too long.
+                                None if max_batch_age.is_zero() => thread::park(),
+                                None => thread::park_timeout(max_batch_age),
                                 Some(None) => break,
                                 _ => unreachable!(),
                             }
@@ -117,25 +158,148 @@ impl DataExporter {
 
         Self {
             bytes_len,
-            bytes_watermark,
+            bytes_capacity: cfg.buf_size,
+            bytes_watermark: cfg.buf_watermark,
+            overflow: cfg.overflow,
+            overflows,
+            drained,
             data_queue,
-            task_id,
-            exporter,
+            exporter: Some(exporter),
+            precision,
+            interrupted,
+        }
+    }
+
+    /// Line protocol timestamp of `ns_ts`, in the precision of the sink.
+    pub const fn timestamp(&self, ns_ts: u64) -> i64 {
+        (ns_ts / self.precision) as i64
+    }
+
+    fn unpark(&self) {
+        if let Some(exporter) = &self.exporter {
+            exporter.thread().unpark();
         }
     }
 
     pub fn flush(&self) {
-        self.exporter.thread().unpark();
+        self.unpark();
     }
 
-    pub fn schedule(&self, data: Data) {
+    /// Block until the exporter thread has found the queue empty, it then parks
+    /// until it is unparked.
+    #[cfg(test)]
+    fn wait_drained(&self) {
+        let (lock, cvar) = &*self.drained;
+        drop(cvar.wait_while(lock.lock().unwrap(), |n| *n == 0).unwrap());
+    }
+
+    /// Queue the data, an error is returned to the guest if it is refused.
+    pub fn schedule(&self, data: Data) -> Result<(), String> {
         let encoded_len = data.encoded_len();
+        if !self.reserve(encoded_len) {
+            self.overflows.fetch_add(1, Ordering::Relaxed);
+            return match self.overflow {
+                OverflowPolicy::Error => Err("Data export buffer is full".to_string()),
+                _ => Ok(()),
+            };
+        }
         self.data_queue.push(Some(data));
         // No critical section, relaxed ordering is fine.
         let prev = self.bytes_len.fetch_add(encoded_len, Ordering::Relaxed);
         if prev > self.bytes_watermark {
-            self.exporter.thread().unpark();
+            self.unpark();
+        }
+        Ok(())
+    }
+
+    /// Make room for `len` bytes by the overflow policy, returns `false` if the data must be discarded.
+    /// Data larger than the capacity is only taken into an empty buffer.
+    /// A blocked guest gives up, and its data is discarded, once its task is interrupted.
+    fn reserve(&self, len: usize) -> bool {
+        let fits = || {
+            let used = self.bytes_len.load(Ordering::Relaxed);
+            used == 0 || used + len <= self.bytes_capacity
+        };
+        if fits() {
+            return true;
+        }
+        match self.overflow {
+            OverflowPolicy::Block => {
+                // The exporter notifies once it has emptied the queue.
+                self.unpark();
+                let (lock, cvar) = &*self.drained;
+                let mut guard = lock.lock().unwrap();
+                loop {
+                    let (next, res) = cvar
+                        .wait_timeout_while(guard, BLOCK_CHECK_INTERVAL, |_| !fits())
+                        .unwrap();
+                    if !res.timed_out() {
+                        return true;
+                    }
+                    if self.interrupted.load(Ordering::SeqCst) {
+                        return false;
+                    }
+                    guard = next;
+                }
+            }
+            OverflowPolicy::DropOldest => {
+                while !fits() {
+                    let Some(Some(oldest)) = self.data_queue.pop() else {
+                        break;
+                    };
+                    self.bytes_len
+                        .fetch_sub(oldest.encoded_len(), Ordering::Relaxed);
+                    self.overflows.fetch_add(1, Ordering::Relaxed);
+                }
+                true
+            }
+            OverflowPolicy::DropNewest | OverflowPolicy::Error => false,
+        }
+    }
+}
+
+/// Where the data exported by guests goes.
+#[derive(Clone)]
+pub enum Sink {
+    Rpc(Box<RpcClient>),
+    Influxdb(InfluxClient),
+    File(Arc<FileSink>),
+}
+
+impl Sink {
+    pub async fn send(&self, req: &ExportDataReq) -> anyhow::Result<()> {
+        match self {
+            Self::Rpc(client) => client.export_data(req.clone()).await,
+            Self::Influxdb(client) => client.write(req).await,
+            Self::File(file) => file.write(req),
+        }
+    }
+
+    /// Nanoseconds in one unit of the timestamps it takes.
+    const fn precision(&self) -> u64 {
+        match self {
+            Self::Influxdb(client) => client.precision().nanos(),
+            Self::Rpc(_) | Self::File(_) => 1,
+        }
+    }
+}
+
+/// Send the data, or spool it if the backend is unreachable or older data is still spooled.
+fn export(rt: &Runtime, sink: &Sink, spool: Option<&Spool>, req: ExportDataReq) {
+    let Some(spool) = spool else {
+        if let Err(e) = rt.block_on(sink.send(&req)) {
+            tracing::warn!("Failed to export data: {}", e);
         }
+        return;
+    };
+    // keep the order, spooled data goes first
+    if !spool.is_empty() {
+        spool.store(&req);
+        return;
+    }
+    if let Err(e) = rt.block_on(sink.send(&req)) {
+        tracing::warn!("Failed to export data, spooling it: {}", e);
+        spool.store(&req);
     }
 }
 
@@ -143,14 +307,21 @@ impl Drop for DataExporter {
     fn drop(&mut self) {
         // Notify the consumer that there is no more data.
         self.data_queue.push(None);
-        self.exporter.thread().unpark();
+        self.unpark();
+        // Wait for the queued data to be sent, so the task finishes with its data delivered.
+        if let Some(exporter) = self.exporter.take() {
+            let _ = exporter.join();
+        }
     }
 }
 
 #[derive(Clone)]
 pub struct Ctx {
     pub instance_id: String,
-    pub exporter: Arc<DataExporter>,
+    pub task_id: String,
+    /// `None` if the data is not sent anywhere
+    pub exporter: Option<Arc<DataExporter>>,
+    pub metrics: Option<TaskMetrics>,
 }
 
 #[derive(Clone)]
@@ -160,8 +331,8 @@ pub struct DataExportCtx {
 
 impl profiling::data_export::common::Host for DataExportCtx {
     fn flush_buf(&mut self) -> wasmtime::Result<Result<(), String>> {
-        if let Some(ctx) = &mut self.ctx {
-            ctx.exporter.flush();
+        if let Some(exporter) = self.ctx.as_ref().and_then(|it| it.exporter.as_ref()) {
+            exporter.flush();
         }
         Ok(Ok(()))
     }
@@ -169,7 +340,7 @@ impl profiling::data_export::common::Host for DataExportCtx {
 
 impl profiling::data_export::file::Host for DataExportCtx {
     fn export_bytes(&mut self, bytes: Vec<u8>) -> wasmtime::Result<Result<(), String>> {
-        let Some(ctx) = &mut self.ctx else {
+        let Some(exporter) = self.ctx.as_ref().and_then(|it| it.exporter.as_ref()) else {
             return Ok(Ok(()));
         };
 
@@ -177,9 +348,7 @@ impl profiling::data_export::file::Host for DataExportCtx {
             ty: DataType::File as _,
             bytes,
         };
-        ctx.exporter.schedule(data);
-
-        Ok(Ok(()))
+        Ok(exporter.schedule(data))
     }
 }
 
@@ -190,14 +359,21 @@ impl profiling::data_export::metric::Host for DataExportCtx {
         };
 
         let tags = &mut sample.tags;
-        tags.push(("task_id".to_string(), ctx.exporter.task_id.clone()));
+        tags.push(("task_id".to_string(), ctx.task_id.clone()));
         tags.push(("instance_id".to_string(), ctx.instance_id.clone()));
 
+        if let Some((metrics, value)) = ctx.metrics.as_mut().zip(metric_value(&sample.value)) {
+            metrics.record(&sample.name, value, &sample.tags);
+        }
+        let Some(exporter) = &ctx.exporter else {
+            return Ok(Ok(()));
+        };
+
         let lp = LineProtocolBuilder::new().measurement(&sample.name);
         let lp = sample.tags.iter().fold(lp, |lp, (k, v)| lp.tag(k, v));
         let lp = lp.field::<WitFieldValue>("value", sample.value);
         let bytes = if let Some(ts) = sample.ns_ts {
-            lp.timestamp(ts as i64).close_line().build()
+            lp.timestamp(exporter.timestamp(ts)).close_line().build()
         } else {
             lp.close_line().build()
         };
@@ -206,9 +382,7 @@ impl profiling::data_export::metric::Host for DataExportCtx {
             ty: DataType::LineProtocol as _,
             bytes,
         };
-        ctx.exporter.schedule(data);
-
-        Ok(Ok(()))
+        Ok(exporter.schedule(data))
     }
 }
 
@@ -219,9 +393,21 @@ impl profiling::data_export::measurement::Host for DataExportCtx {
         };
 
         let tags = &mut point.tags;
-        tags.push(("task_id".to_string(), ctx.exporter.task_id.clone()));
+        tags.push(("task_id".to_string(), ctx.task_id.clone()));
         tags.push(("instance_id".to_string(), ctx.instance_id.clone()));
 
+        if let Some(metrics) = &mut ctx.metrics {
+            for (key, value) in &point.fields {
+                if let Some(value) = metric_value(value) {
+                    let name = format!("{}.{}", point.name, key);
+                    metrics.record(&name, value, &point.tags);
+                }
+            }
+        }
+        let Some(exporter) = &ctx.exporter else {
+            return Ok(Ok(()));
+        };
+
         let lp = LineProtocolBuilder::new().measurement(&point.name);
         let lp = point.tags.iter().fold(lp, |lp, (k, v)| lp.tag(k, v));
 
@@ -234,7 +420,7 @@ impl profiling::data_export::measurement::Host for DataExportCtx {
         let lp = lp.field::<WitFieldValue>(&first_key, first_val);
         let lp = fields.fold(lp, |lp, (k, v)| lp.field::<WitFieldValue>(&k, v));
         let bytes = if let Some(ts) = point.ns_ts {
-            lp.timestamp(ts as i64).close_line().build()
+            lp.timestamp(exporter.timestamp(ts)).close_line().build()
         } else {
             lp.close_line().build()
         };
@@ -243,9 +429,7 @@ impl profiling::data_export::measurement::Host for DataExportCtx {
             ty: DataType::LineProtocol as _,
             bytes,
         };
-        ctx.exporter.schedule(data);
-
-        Ok(Ok(()))
+        Ok(exporter.schedule(data))
     }
 }
 
@@ -255,3 +439,84 @@ pub fn add_to_linker<T>(
 ) -> anyhow::Result<()> {
     Imports::add_to_linker(l, f)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::FileSinkConfig;
+    use crate::test_utils::TempDir;
+
+    fn line(n: usize) -> Data {
+        Data {
+            ty: DataType::LineProtocol as _,
+            bytes: format!("m v={n}i\n").into_bytes(),
+        }
+    }
+
+    /// Export `n` lines through a file sink, returns the values written and the overflows.
+    fn export_lines(
+        name: &str,
+        overflow: OverflowPolicy,
+        buf_size: usize,
+        n: usize,
+    ) -> (Vec<usize>, u64) {
+        let dir = TempDir::new(&format!("export-{name}"));
+        let sink_cfg = FileSinkConfig {
+            enable: true,
+            dir: dir.path().to_string_lossy().into_owned(),
+            ..Default::default()
+        };
+        let cfg = DataExportConfig {
+            buf_size,
+            // only the buffer being full wakes up the exporter
+            buf_watermark: buf_size,
+            overflow,
+            max_batch_age: 0,
+            max_batch_size: 1 << 20,
+            spool: Default::default(),
+        };
+        let overflows = Arc::new(AtomicU64::new(0));
+        let sink = FileSink::open(&sink_cfg).unwrap();
+        let exporter = DataExporter::new(
+            &cfg,
+            "t".to_owned(),
+            Sink::File(Arc::new(sink)),
+            None,
+            overflows.clone(),
+            Arc::default(),
+        );
+        // let the exporter park before filling the buffer
+        exporter.wait_drained();
+        for n in 0..n {
+            exporter.schedule(line(n)).unwrap();
+        }
+        drop(exporter);
+
+        let written = std::fs::read_to_string(dir.join("metrics.lp")).unwrap();
+        let values = written
+            .lines()
+            .map(|it| it["m v=".len()..it.len() - 1].parse().unwrap())
+            .collect();
+        (values, overflows.load(Ordering::Relaxed))
+    }
+
+    #[test]
+    fn test_overflow_block() {
+        let len = line(0).encoded_len();
+        let (values, overflows) = export_lines("block", OverflowPolicy::Block, 3 * len, 100);
+        assert_eq!(values, (0..100).collect::<Vec<_>>());
+        assert_eq!(overflows, 0);
+    }
+
+    #[test]
+    fn test_overflow_drop() {
+        let len = line(0).encoded_len();
+        let (values, overflows) = export_lines("newest", OverflowPolicy::DropNewest, 3 * len, 5);
+        assert_eq!(values, [0, 1, 2]);
+        assert_eq!(overflows, 2);
+
+        let (values, overflows) = export_lines("oldest", OverflowPolicy::DropOldest, 3 * len, 5);
+        assert_eq!(values, [2, 3, 4]);
+        assert_eq!(overflows, 2);
+    }
+}
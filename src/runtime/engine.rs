@@ -12,37 +12,158 @@
 // You should have received a copy of the GNU Lesser General Public License along with Performance Savior Home (PSH). If not,
 // see <https://www.gnu.org/licenses/>.
 
-use std::thread;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
 use std::time::Duration;
 
-use anyhow::Context;
+use anyhow::{bail, Context};
 use wasmtime::component::{Component, Linker};
-use wasmtime::{Engine, Store};
+use wasmtime::{Engine, Precompiled, Store, Trap};
 use wasmtime_wasi::bindings::sync::Command;
+use wasmtime_wasi::I32Exit;
 
-use super::PshState;
+use super::{inspect, ComponentCache, IncompatibleComponent, Inspection, PshState};
+
+/// How a guest command ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitStatus {
+    /// Returned from `wasi:cli/run` or called `exit`, with the exit code.
+    Exited(i32),
+    /// Stopped by the epoch deadline.
+    Interrupted,
+}
+
+/// Stops a `PshEngine` from another thread by triggering its epoch deadline.
+#[derive(Clone)]
+pub struct Interrupter {
+    engine: Engine,
+    interrupted: Arc<AtomicBool>,
+}
+
+impl Interrupter {
+    pub fn interrupt(&self) {
+        self.interrupted.store(true, Ordering::SeqCst);
+        self.engine.increment_epoch();
+    }
+}
+
+/// Triggers the epoch deadline once the time slice has passed, unless dropped before.
+struct Deadline {
+    done: Arc<(Mutex<bool>, Condvar)>,
+    timer: Option<JoinHandle<()>>,
+}
+
+impl Deadline {
+    fn start(engine: Engine, time_slice: Duration) -> Self {
+        let done = Arc::new((Mutex::new(false), Condvar::new()));
+        let timer = {
+            let done = done.clone();
+            thread::spawn(move || {
+                let (lock, cvar) = &*done;
+                let (guard, res) = cvar
+                    .wait_timeout_while(lock.lock().unwrap(), time_slice, |done| !*done)
+                    .unwrap();
+                drop(guard);
+                if res.timed_out() {
+                    engine.increment_epoch();
+                }
+            })
+        };
+        Self {
+            done,
+            timer: Some(timer),
+        }
+    }
+}
+
+impl Drop for Deadline {
+    fn drop(&mut self) {
+        let (lock, cvar) = &*self.done;
+        *lock.lock().unwrap() = true;
+        cvar.notify_one();
+        if let Some(timer) = self.timer.take() {
+            let _ = timer.join();
+        }
+    }
+}
 
 pub struct PshEngine {
     pub engine: Engine,
     pub store: Store<PshState>,
     pub linker: Linker<PshState>,
+    pub interrupted: Arc<AtomicBool>,
+    pub cache: Option<Arc<ComponentCache>>,
+    /// Accept components precompiled by `psh compile`, only for trusted sources
+    pub allow_precompiled: bool,
 }
 
 impl PshEngine {
-    pub fn run(mut self, binary: &[u8], time_slice: u64) -> anyhow::Result<()> {
-        let component =
-            Component::from_binary(&self.engine, binary).context("Failed to load component!")?;
-        let cmd = Command::instantiate(&mut self.store, &component, &self.linker)
-            .context("Failed to instantiate Wasi Command!")?;
+    pub fn interrupter(&self) -> Interrupter {
+        Interrupter {
+            engine: self.engine.clone(),
+            interrupted: self.interrupted.clone(),
+        }
+    }
+
+    pub fn load(&self, binary: &[u8]) -> anyhow::Result<Component> {
+        if self.engine.detect_precompiled(binary) == Some(Precompiled::Component) {
+            if !self.allow_precompiled {
+                bail!("Precompiled components are not allowed");
+            }
+            // SAFETY: precompiled components are only allowed from trusted sources.
+            return unsafe { Component::deserialize(&self.engine, binary) };
+        }
+        self.cache.as_ref().map_or_else(
+            || Component::from_binary(&self.engine, binary),
+            |cache| cache.load(&self.engine, binary),
+        )
+    }
+
+    /// List imports and exports of a component and check them against the linker.
+    pub fn inspect(&self, component: &Component) -> Inspection {
+        inspect::inspect(&self.engine, &self.linker, component)
+    }
+
+    pub fn instantiate(&mut self, binary: &[u8]) -> anyhow::Result<Command> {
+        let component = self.load(binary).context("Failed to load component!")?;
+        let inspection = self.inspect(&component);
+        if !inspection.problems.is_empty() {
+            return Err(IncompatibleComponent(inspection.problems).into());
+        }
+        Command::instantiate(&mut self.store, &component, &self.linker)
+            .context("Failed to instantiate Wasi Command!")
+    }
+
+    pub fn call(mut self, cmd: Command, time_slice: u64) -> anyhow::Result<ExitStatus> {
         self.store.set_epoch_deadline(1);
-        thread::spawn(move || {
-            thread::sleep(Duration::from_millis(time_slice as _));
-            self.engine.increment_epoch();
-        });
-        let _ = cmd
-            .wasi_cli_run()
-            .call_run(&mut self.store)
-            .context("Failed to run component")?;
-        Ok(())
+        // An interrupt before the deadline was set would be missed by the guest.
+        if self.interrupted.load(Ordering::SeqCst) {
+            return Ok(ExitStatus::Interrupted);
+        }
+        // stopped when the guest returns, by itself or interrupted
+        let _deadline = Deadline::start(self.engine.clone(), Duration::from_millis(time_slice));
+        match cmd.wasi_cli_run().call_run(&mut self.store) {
+            Ok(Ok(())) => Ok(ExitStatus::Exited(0)),
+            Ok(Err(())) => Ok(ExitStatus::Exited(1)),
+            Err(e) => {
+                if let Some(I32Exit(code)) = e.downcast_ref::<I32Exit>() {
+                    return Ok(ExitStatus::Exited(*code));
+                }
+                if e.downcast_ref::<Trap>() == Some(&Trap::Interrupt) {
+                    return Ok(ExitStatus::Interrupted);
+                }
+                Err(e).context("Failed to run component")
+            }
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn run(mut self, binary: &[u8], time_slice: u64) -> anyhow::Result<()> {
+        let cmd = self.instantiate(binary)?;
+        match self.call(cmd, time_slice)? {
+            ExitStatus::Exited(_) => Ok(()),
+            ExitStatus::Interrupted => bail!("Component was interrupted by the deadline"),
+        }
     }
 }
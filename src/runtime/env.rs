@@ -0,0 +1,32 @@
+// Copyright (c) 2023-2024 Optimatist Technology Co., Ltd. All rights reserved.
+// DO NOT ALTER OR REMOVE COPYRIGHT NOTICES OR THIS FILE HEADER.
+//
+// This file is part of PSH.
+//
+// PSH is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License
+// as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+//
+// PSH is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even
+// the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License along with Performance Savior Home (PSH). If not,
+// see <https://www.gnu.org/licenses/>.
+
+//! Variables psh provides to every guest.
+//!
+//! They are all prefixed with [`PREFIX`], host and task variables with this prefix
+//! are never passed to the guest so it can rely on these values.
+
+pub const PREFIX: &str = "PSH_";
+/// Milliseconds left until the task should return
+pub const TASK_TIME_SLICE: &str = "PSH_TASK_TIME_SLICE";
+pub const TASK_ID: &str = "PSH_TASK_ID";
+pub const INSTANCE_ID: &str = "PSH_INSTANCE_ID";
+/// Architecture of the host, e.g. `x86_64`, `aarch64`
+pub const HOST_ARCH: &str = "PSH_HOST_ARCH";
+/// Same as [`TASK_TIME_SLICE`], kept for existing components
+pub const LEGACY_TASK_TIME_SLICE: &str = "TASK_TIME_SLICE";
+
+pub fn is_reserved(name: &str) -> bool {
+    name.starts_with(PREFIX) || name == LEGACY_TASK_TIME_SLICE
+}
@@ -0,0 +1,172 @@
+// Copyright (c) 2023-2024 Optimatist Technology Co., Ltd. All rights reserved.
+// DO NOT ALTER OR REMOVE COPYRIGHT NOTICES OR THIS FILE HEADER.
+//
+// This file is part of PSH.
+//
+// PSH is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License
+// as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+//
+// PSH is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even
+// the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License along with Performance Savior Home (PSH). If not,
+// see <https://www.gnu.org/licenses/>.
+
+use std::fs::{self, DirBuilder, File, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use anyhow::{Context, Result};
+use chrono::Utc;
+use psh_proto::{DataType, ExportDataReq};
+
+use super::scratch::dir_name;
+use crate::config::FileSinkConfig;
+
+const METRICS: &str = "metrics.lp";
+const ARTIFACTS: &str = "artifacts";
+
+/// Writes exported data to local files, line protocol to rotating `metrics.lp`
+/// and bytes to a directory of each task under `artifacts`.
+pub struct FileSink {
+    dir: PathBuf,
+    max_file_size: u64,
+    max_files: usize,
+    /// `metrics.lp` and its size
+    metrics: Mutex<(File, u64)>,
+    seq: AtomicU64,
+}
+
+impl FileSink {
+    pub fn open(cfg: &FileSinkConfig) -> Result<Self> {
+        let dir = PathBuf::from(&cfg.dir);
+        DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&dir)
+            .with_context(|| format!("Failed to create file sink dir {}", dir.display()))?;
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join(METRICS))?;
+        let size = file.metadata()?.len();
+
+        Ok(Self {
+            dir,
+            max_file_size: cfg.max_file_size,
+            max_files: cfg.max_files,
+            metrics: Mutex::new((file, size)),
+            seq: AtomicU64::new(0),
+        })
+    }
+
+    pub fn write(&self, req: &ExportDataReq) -> Result<()> {
+        for data in &req.data {
+            match DataType::try_from(data.ty) {
+                Ok(DataType::LineProtocol) => self.append_metrics(&data.bytes)?,
+                Ok(DataType::File) => self.write_artifact(&req.task_id, &data.bytes)?,
+                Err(_) => tracing::warn!("Discarded exported data of unknown type {}", data.ty),
+            }
+        }
+        Ok(())
+    }
+
+    fn append_metrics(&self, bytes: &[u8]) -> Result<()> {
+        let mut metrics = self.metrics.lock().unwrap();
+        let (file, size) = &mut *metrics;
+        if *size > 0 && *size + bytes.len() as u64 > self.max_file_size {
+            self.rotate()?;
+            *file = File::create(self.dir.join(METRICS))?;
+            *size = 0;
+        }
+        file.write_all(bytes)?;
+        *size += bytes.len() as u64;
+        drop(metrics);
+        Ok(())
+    }
+
+    /// Shift `metrics.lp.N` to `metrics.lp.N+1`, the oldest file is overwritten.
+    fn rotate(&self) -> Result<()> {
+        let path = |n: usize| match n {
+            0 => self.dir.join(METRICS),
+            n => self.dir.join(format!("{METRICS}.{n}")),
+        };
+        for n in (0..self.max_files.saturating_sub(1)).rev() {
+            match fs::rename(path(n), path(n + 1)) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn write_artifact(&self, task_id: &str, bytes: &[u8]) -> Result<()> {
+        let dir = self.dir.join(ARTIFACTS).join(dir_name(task_id));
+        DirBuilder::new().recursive(true).mode(0o700).create(&dir)?;
+        let name = format!(
+            "{}-{}",
+            Utc::now().timestamp_millis(),
+            self.seq.fetch_add(1, Ordering::Relaxed)
+        );
+        fs::write(dir.join(name), bytes)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use psh_proto::Data;
+
+    use super::*;
+    use crate::test_utils::TempDir;
+
+    fn sink(name: &str, max_files: usize) -> (FileSink, TempDir) {
+        let dir = TempDir::new(&format!("sink-{name}"));
+        let cfg = FileSinkConfig {
+            enable: true,
+            dir: dir.path().to_string_lossy().into_owned(),
+            max_file_size: 8,
+            max_files,
+        };
+        (FileSink::open(&cfg).unwrap(), dir)
+    }
+
+    /// Write lines of 6 bytes, so each file takes one line.
+    fn write_lines(sink: &FileSink, n: usize) {
+        for n in 0..n {
+            let req = ExportDataReq {
+                task_id: "t".to_owned(),
+                data: vec![Data {
+                    ty: DataType::LineProtocol as _,
+                    bytes: format!("m v={n}\n").into_bytes(),
+                }],
+            };
+            sink.write(&req).unwrap();
+        }
+    }
+
+    fn read(dir: &TempDir, name: &str) -> String {
+        fs::read_to_string(dir.join(name)).unwrap()
+    }
+
+    #[test]
+    fn test_rotate_single_file() {
+        let (sink, dir) = sink("single", 1);
+        write_lines(&sink, 3);
+        assert_eq!(read(&dir, METRICS), "m v=2\n");
+        assert!(!dir.join(format!("{METRICS}.1")).exists());
+    }
+
+    #[test]
+    fn test_rotate_files() {
+        let (sink, dir) = sink("files", 3);
+        write_lines(&sink, 5);
+        assert_eq!(read(&dir, METRICS), "m v=4\n");
+        assert_eq!(read(&dir, &format!("{METRICS}.1")), "m v=3\n");
+        assert_eq!(read(&dir, &format!("{METRICS}.2")), "m v=2\n");
+        assert!(!dir.join(format!("{METRICS}.3")).exists());
+    }
+}
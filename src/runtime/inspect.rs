@@ -0,0 +1,138 @@
+// Copyright (c) 2023-2024 Optimatist Technology Co., Ltd. All rights reserved.
+// DO NOT ALTER OR REMOVE COPYRIGHT NOTICES OR THIS FILE HEADER.
+//
+// This file is part of PSH.
+//
+// PSH is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License
+// as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+//
+// PSH is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even
+// the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License along with Performance Savior Home (PSH). If not,
+// see <https://www.gnu.org/licenses/>.
+
+use std::collections::HashSet;
+use std::fmt;
+
+use wasmtime::component::types::ComponentItem;
+use wasmtime::component::{Component, Linker, ResourceType, Val};
+use wasmtime::{Engine, StoreContextMut};
+
+use super::PshState;
+
+/// Imports and exports of a component, checked against what a `Linker` provides.
+pub struct Inspection {
+    pub imports: Vec<(String, &'static str)>,
+    pub exports: Vec<(String, &'static str)>,
+    /// Why the component can't run, empty if it can
+    pub problems: Vec<String>,
+}
+
+/// A component that can't run with the host interfaces of psh.
+#[derive(Debug)]
+pub struct IncompatibleComponent(pub Vec<String>);
+
+impl fmt::Display for IncompatibleComponent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Incompatible component: {}", self.0.join("; "))
+    }
+}
+
+impl std::error::Error for IncompatibleComponent {}
+
+const fn kind(item: &ComponentItem) -> &'static str {
+    match item {
+        ComponentItem::ComponentFunc(_) => "func",
+        ComponentItem::CoreFunc(_) => "core func",
+        ComponentItem::Module(_) => "module",
+        ComponentItem::Component(_) => "component",
+        ComponentItem::ComponentInstance(_) => "instance",
+        ComponentItem::Type(_) => "type",
+        ComponentItem::Resource(_) => "resource",
+    }
+}
+
+pub fn inspect(engine: &Engine, linker: &Linker<PshState>, component: &Component) -> Inspection {
+    let ty = component.component_type();
+    let imports: Vec<_> = ty.imports(engine).collect();
+    let exports: Vec<_> = ty.exports(engine).collect();
+
+    let mut problems = vec![];
+    if !exports
+        .iter()
+        .any(|(name, _)| name.starts_with("wasi:cli/run@"))
+    {
+        problems.push("component does not export `wasi:cli/run`".to_owned());
+    }
+
+    // The linker stops at the first bad import, so stub it out and try again
+    // until every bad import is found. Each import is stubbed at most once.
+    let mut linker = linker.clone();
+    linker.allow_shadowing(true);
+    let mut stubbed = HashSet::new();
+    while let Err(e) = linker.instantiate_pre(component) {
+        problems.push(format!("{e:#}"));
+        let outer = e.to_string();
+        let Some((name, item)) = imports
+            .iter()
+            .find(|(name, _)| outer.contains(&format!("`{name}`")))
+        else {
+            break;
+        };
+        if !stubbed.insert(*name) {
+            problems.push(format!("`{name}` is still incompatible after stubbing it"));
+            break;
+        }
+        if stub(engine, &mut linker, name, item).is_err() {
+            break;
+        }
+    }
+
+    Inspection {
+        imports: imports
+            .iter()
+            .map(|(name, item)| (name.to_string(), kind(item)))
+            .collect(),
+        exports: exports
+            .iter()
+            .map(|(name, item)| (name.to_string(), kind(item)))
+            .collect(),
+        problems,
+    }
+}
+
+fn trap(_: StoreContextMut<'_, PshState>, _: &[Val], _: &mut [Val]) -> anyhow::Result<()> {
+    anyhow::bail!("Called a stub of an incompatible import")
+}
+
+/// Define `name` with functions that trap, shadowing the existing definition.
+fn stub(
+    engine: &Engine,
+    linker: &mut Linker<PshState>,
+    name: &str,
+    item: &ComponentItem,
+) -> anyhow::Result<()> {
+    match item {
+        ComponentItem::ComponentFunc(_) => linker.root().func_new(name, trap)?,
+        ComponentItem::Resource(_) => {
+            linker
+                .root()
+                .resource(name, ResourceType::host::<()>(), |_, _| Ok(()))?;
+        }
+        ComponentItem::ComponentInstance(instance) => {
+            let mut linker = linker.instance(name)?;
+            for (name, item) in instance.exports(engine) {
+                match item {
+                    ComponentItem::ComponentFunc(_) => linker.func_new(name, trap)?,
+                    ComponentItem::Resource(_) => {
+                        linker.resource(name, ResourceType::host::<()>(), |_, _| Ok(()))?;
+                    }
+                    _ => {}
+                }
+            }
+        }
+        _ => anyhow::bail!("unable to stub {}", kind(item)),
+    }
+    Ok(())
+}
@@ -13,128 +13,377 @@
 // see <https://www.gnu.org/licenses/>.
 
 mod builder;
+mod cache;
 mod data_export;
 mod engine;
+pub mod env;
+mod file_sink;
+mod inspect;
+mod report;
+mod scratch;
+pub mod signature;
 mod state;
+mod table;
 
 #[cfg(test)]
 mod tests;
 
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::mpsc::{channel, Receiver, Sender};
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::thread::JoinHandle;
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 pub use builder::PshEngineBuilder;
+pub use cache::ComponentCache;
 use chrono::{DateTime, Utc};
-use data_export::{Ctx, DataExportCtx, DataExporter};
-pub use engine::PshEngine;
+use data_export::{Ctx, DataExporter};
+pub use data_export::{DataExportCtx, Sink};
+pub use engine::{ExitStatus, Interrupter, PshEngine};
+use file_sink::FileSink;
+pub use inspect::{IncompatibleComponent, Inspection};
+pub use report::{TaskOutput, TaskReport, Usage};
+use scratch::ScratchDir;
+use signature::{UntrustedComponent, Verifier};
 pub use state::PshState;
+pub use table::TaskState;
+use table::TaskTable;
+use wasmtime_wasi::pipe::MemoryOutputPipe;
+use wasmtime_wasi::{DirPerms, FilePerms};
 
-use crate::services::rpc::RpcClient;
+use crate::config::{
+    DataExportConfig, EnvConfig, FsConfig, LimitsConfig, PreopenConfig, RuntimeConfig,
+};
+use crate::otlp::{GuestMetrics, TaskMetrics};
+use crate::services::spool::Spool;
 
 pub struct Task {
     pub id: Option<String>,
     pub wasm_component: Vec<u8>,
+    /// Detached signature of `wasm_component`, see `signature`
+    pub signature: Option<Vec<u8>>,
     pub wasm_component_args: Vec<String>,
     pub end_time: DateTime<Utc>,
+    /// Overrides `runtime.limits` from the config
+    pub limits: Option<LimitsConfig>,
+    /// Extra environment variables, names reserved by psh are ignored
+    pub envs: Vec<(String, String)>,
+    /// Overrides `runtime.fs.preopens` from the config
+    pub preopens: Option<Vec<PreopenConfig>>,
+    /// Accept a component precompiled by `psh compile`, only for trusted sources
+    pub allow_precompiled: bool,
+    /// Capture up to this many bytes of stdout and stderr each into the report,
+    /// instead of inheriting the stdio of psh. Writes beyond it fail.
+    pub capture_output: Option<usize>,
 }
 
 pub struct TaskRuntime {
-    tx: Sender<Task>,
-    rx: Option<Receiver<Task>>,
-    len: Arc<AtomicUsize>,
-    finished_task_id: Arc<Mutex<Vec<String>>>,
+    tx: Mutex<Option<Sender<(String, Task)>>>,
+    rx: Mutex<Option<Receiver<(String, Task)>>>,
+    cfg: RuntimeConfig,
+    table: Arc<TaskTable>,
+}
+
+/// Everything a worker needs to run a task, shared by all workers.
+struct WorkerCtx {
+    /// `None` discards the exported data
+    sink: Option<Sink>,
+    spool: Option<Arc<Spool>>,
+    data_export: DataExportConfig,
+    guest_metrics: Option<Arc<GuestMetrics>>,
+    instance_id: String,
+    env: EnvConfig,
+    limits: LimitsConfig,
+    fs: FsConfig,
+    cache: Option<Arc<ComponentCache>>,
+    verifier: Verifier,
+}
+
+impl WorkerCtx {
+    fn run(&self, key: String, task: &Task, table: &TaskTable) -> TaskReport {
+        let started_at = Utc::now();
+        let usage = Usage::thread();
+
+        let output = task.capture_output.map(|capacity| {
+            (
+                MemoryOutputPipe::new(capacity),
+                MemoryOutputPipe::new(capacity),
+            )
+        });
+
+        let data_overflows = Arc::new(AtomicU64::new(0));
+        let (state, exit_code, error) =
+            match self.execute(&key, task, table, output.clone(), data_overflows.clone()) {
+                Ok(ExitStatus::Exited(0)) => (TaskState::Succeeded, Some(0), None),
+                Ok(ExitStatus::Exited(code)) => (TaskState::Failed, Some(code), None),
+                Ok(ExitStatus::Interrupted) | Err(_) if table.is_cancelled(&key) => {
+                    (TaskState::Cancelled, None, None)
+                }
+                Ok(ExitStatus::Interrupted) => (TaskState::TimedOut, None, None),
+                Err(e) if e.is::<IncompatibleComponent>() || e.is::<UntrustedComponent>() => {
+                    (TaskState::Rejected, None, Some(format!("{:#}", e)))
+                }
+                Err(e) => (TaskState::Failed, None, Some(format!("{:#}", e))),
+            };
+
+        TaskReport {
+            id: task.id.clone(),
+            key,
+            state,
+            error,
+            exit_code,
+            started_at,
+            finished_at: Utc::now(),
+            usage: Usage::thread().since(usage),
+            output: output.map(|(stdout, stderr)| TaskOutput {
+                stdout: stdout.contents().to_vec(),
+                stderr: stderr.contents().to_vec(),
+            }),
+            data_overflows: data_overflows.load(Ordering::Relaxed),
+        }
+    }
+
+    fn execute(
+        &self,
+        key: &str,
+        task: &Task,
+        table: &TaskTable,
+        output: Option<(MemoryOutputPipe, MemoryOutputPipe)>,
+        data_overflows: Arc<AtomicU64>,
+    ) -> Result<ExitStatus> {
+        self.verifier
+            .check(&task.wasm_component, task.signature.as_deref())?;
+
+        let task_time_slice = {
+            let delta = task.end_time.timestamp_millis() - Utc::now().timestamp_millis();
+            delta.max(0) as u64
+        };
+        let envs = [
+            (env::TASK_TIME_SLICE, task_time_slice.to_string()),
+            (env::LEGACY_TASK_TIME_SLICE, task_time_slice.to_string()),
+            (env::TASK_ID, key.to_owned()),
+            (env::INSTANCE_ID, self.instance_id.clone()),
+            (env::HOST_ARCH, std::env::consts::ARCH.to_owned()),
+        ];
+
+        // a guest blocked on a full export buffer gives up once the task is interrupted
+        let interrupted = Arc::<AtomicBool>::default();
+        // data of local tasks is kept under their key
+        let task_id = task.id.clone().unwrap_or_else(|| key.to_owned());
+        let exporter = match &self.sink {
+            // the server only takes data of its own tasks
+            Some(Sink::Rpc(_)) if task.id.is_none() => None,
+            sink => sink.clone().map(|sink| {
+                Arc::new(DataExporter::new(
+                    &self.data_export,
+                    task_id.clone(),
+                    sink,
+                    self.spool.clone(),
+                    data_overflows,
+                    interrupted.clone(),
+                ))
+            }),
+        };
+        let ctx = (exporter.is_some() || self.guest_metrics.is_some()).then(|| Ctx {
+            instance_id: self.instance_id.clone(),
+            task_id,
+            exporter,
+            metrics: self.guest_metrics.clone().map(TaskMetrics::new),
+        });
+        let data_export_ctx = DataExportCtx { ctx };
+
+        let scratch = (self.fs.scratch.enable)
+            .then(|| ScratchDir::create(&self.fs.scratch.dir, key))
+            .transpose()?;
+        let mut builder =
+            PshEngineBuilder::new().preopens(task.preopens.as_ref().unwrap_or(&self.fs.preopens));
+        if let Some(scratch) = &scratch {
+            builder = builder.wasi_preopened_dir(
+                scratch.path().to_string_lossy(),
+                &self.fs.scratch.guest,
+                DirPerms::all(),
+                FilePerms::all(),
+            );
+        }
+        builder = match output {
+            Some((stdout, stderr)) => builder.wasi_stdout(stdout).wasi_stderr(stderr),
+            None => builder.wasi_inherit_stdio(),
+        };
+        let mut engine = builder
+            .inherit_envs(&self.env)
+            .task_envs(&task.envs)
+            .wasi_envs(&envs)
+            .wasi_args(&task.wasm_component_args)
+            .allow_perf_op(true)
+            .allow_system_op(true)
+            .allow_data_export_op(Some(data_export_ctx))
+            .limits(task.limits.clone().unwrap_or_else(|| self.limits.clone()))
+            .component_cache(self.cache.clone())
+            .allow_precompiled(task.allow_precompiled)
+            .interrupted(interrupted)
+            .build()
+            .context("Failed to build PshEngine.")?;
+        table.set_interrupter(key, engine.interrupter());
+
+        let cmd = engine.instantiate(&task.wasm_component)?;
+        table.set_state(key, TaskState::Running);
+        engine.call(cmd, task_time_slice)
+    }
 }
 
 impl TaskRuntime {
-    pub fn new() -> Result<Self> {
+    pub fn new(cfg: RuntimeConfig) -> Result<Self> {
         let (tx, rx) = channel();
 
         Ok(Self {
-            tx,
-            rx: Some(rx),
-            len: Arc::new(AtomicUsize::new(0)),
-            finished_task_id: Arc::new(Mutex::new(vec![])),
+            tx: Mutex::new(Some(tx)),
+            rx: Mutex::new(Some(rx)),
+            cfg,
+            table: Arc::new(TaskTable::default()),
         })
     }
 
-    pub fn schedule(&self, task: Task) -> Result<()> {
-        self.len.fetch_add(1, Ordering::Release);
-        self.tx.send(task)?;
-        Ok(())
+    /// Queue a task, returns its key in the runtime.
+    pub fn schedule(&self, task: Task) -> Result<String> {
+        let Some(tx) = self.tx.lock().unwrap().clone() else {
+            bail!("TaskRuntime is closed");
+        };
+        let key = self.table.insert(task.id.clone())?;
+        tx.send((key.clone(), task))?;
+        Ok(key)
     }
 
+    /// Stop accepting tasks, the spawned thread exits after the scheduled tasks finish.
+    pub fn close(&self) {
+        self.tx.lock().unwrap().take();
+    }
+
+    /// Stop accepting tasks and cancel all queued and running ones.
+    pub fn shutdown(&self) {
+        self.close();
+        self.table.cancel_all();
+    }
+
+    /// No task is queued or running.
     pub fn is_idle(&self) -> bool {
-        let len = self.len.load(Ordering::Acquire);
-        len == 0
+        self.table.is_empty()
+    }
+
+    /// A newly scheduled task would start without waiting for a free worker.
+    pub fn has_capacity(&self) -> bool {
+        self.table.len() < self.max_concurrency()
+    }
+
+    fn max_concurrency(&self) -> usize {
+        self.cfg.max_concurrency.max(1)
+    }
+
+    /// Cancel a queued or running task by its key, the task is reported as cancelled.
+    /// Returns `false` if there is no such task.
+    ///
+    /// Called for the control socket, `psh tasks cancel` and the ids in the heartbeat responses of the server.
+    pub fn cancel(&self, id: &str) -> bool {
+        self.table.cancel(id)
+    }
+
+    /// Report a task that is never scheduled, such as a server task with invalid settings.
+    pub fn reject(&self, report: TaskReport) {
+        self.table.finish(report);
+    }
+
+    /// Reports of server tasks finished since the last call, oldest first.
+    pub fn finished_tasks(&self) -> Vec<TaskReport> {
+        self.table.take_finished()
+    }
+
+    /// Queued and running tasks with their states.
+    pub fn active_tasks(&self) -> Vec<(String, TaskState)> {
+        self.table.states()
     }
 
-    pub fn finished_task_id(&self) -> Option<String> {
-        self.finished_task_id.lock().unwrap().pop()
+    /// Reports of the recently finished tasks, oldest first.
+    /// Unlike `finished_tasks` the reports are kept for later calls.
+    pub fn recent_tasks(&self) -> Vec<TaskReport> {
+        self.table.history()
     }
-    #[allow(clippy::significant_drop_tightening)]
+
     pub fn spawn(
-        &mut self,
-        rpc_client: Option<RpcClient>,
-        data_export_buf_size: usize,
-        data_export_buf_watermark: usize,
+        &self,
+        sink: Option<Sink>,
+        spool: Option<Arc<Spool>>,
+        data_export: DataExportConfig,
+        guest_metrics: Option<Arc<GuestMetrics>>,
         instance_id: String,
     ) -> Result<JoinHandle<()>> {
-        let rx = self
-            .rx
-            .take()
-            .map_or_else(|| panic!("twice spawned"), |rx| rx);
+        let Some(rx) = self.rx.lock().unwrap().take() else {
+            panic!("twice spawned")
+        };
 
-        let envs: Vec<(String, String)> = std::env::vars().collect();
+        let sink = match sink {
+            None if self.cfg.file_sink.enable => {
+                Some(Sink::File(Arc::new(FileSink::open(&self.cfg.file_sink)?)))
+            }
+            sink => sink,
+        };
+        let ctx = Arc::new(WorkerCtx {
+            sink,
+            spool,
+            data_export,
+            guest_metrics,
+            instance_id,
+            env: self.cfg.env.clone(),
+            limits: self.cfg.limits.clone(),
+            fs: self.cfg.fs.clone(),
+            cache: self.cfg.cache.enable.then(|| {
+                Arc::new(ComponentCache::new(
+                    &self.cfg.cache.dir,
+                    self.cfg.cache.max_size,
+                ))
+            }),
+            verifier: Verifier::new(&self.cfg.signature)?,
+        });
 
-        let len = self.len.clone();
-        let finished_task_id = self.finished_task_id.clone();
+        let max_concurrency = self.max_concurrency();
+        let table = self.table.clone();
         let handle = thread::spawn(move || {
-            while let Ok(task) = rx.recv() {
-                let mut envs = envs.clone();
-                let task_time_slice = {
-                    let delta = task.end_time.timestamp_millis() - Utc::now().timestamp_millis();
-                    delta.max(0) as u64
-                };
-                envs.push(("TASK_TIME_SLICE".to_string(), task_time_slice.to_string()));
-
-                #[expect(clippy::significant_drop_in_scrutinee)]
-                let ctx = match (rpc_client.clone(), task.id.clone()) {
-                    (Some(rpc_client), Some(task_id)) => Some(Ctx {
-                        instance_id: instance_id.clone(),
-                        exporter: Arc::new(DataExporter::new(
-                            data_export_buf_size,
-                            data_export_buf_watermark,
-                            task_id,
-                            rpc_client,
-                        )),
-                    }),
-                    _ => None,
-                };
-                let data_export_ctx = DataExportCtx { ctx };
-                let engine = PshEngineBuilder::new()
-                    .wasi_inherit_stdio()
-                    .wasi_envs(&envs)
-                    .wasi_args(&task.wasm_component_args)
-                    .allow_perf_op(true)
-                    .allow_system_op(true)
-                    .allow_data_export_op(Some(data_export_ctx.clone()))
-                    .build()
-                    .context("Failed to build PshEngine.");
-
-                match engine {
-                    Ok(o) => {
-                        let _ = o.run(&task.wasm_component, task_time_slice);
-                    }
-                    Err(e) => eprintln!("{}", e),
-                };
-                if let Some(id) = task.id {
-                    finished_task_id.lock().unwrap().push(id);
+            let mut workers: Vec<JoinHandle<()>> = vec![];
+            while let Ok((key, task)) = rx.recv() {
+                if !table.start(&key, max_concurrency) {
+                    table.finish(TaskReport::aborted(
+                        task.id,
+                        key,
+                        TaskState::Cancelled,
+                        None,
+                    ));
+                    continue;
                 }
-                len.fetch_sub(1, Ordering::Release);
+                workers.retain(|it| !it.is_finished());
+
+                let ctx = ctx.clone();
+                let table = table.clone();
+                let worker = thread::spawn(move || {
+                    // the slot of the task is only freed by `finish`
+                    let report = panic::catch_unwind(AssertUnwindSafe(|| {
+                        ctx.run(key.clone(), &task, &table)
+                    }))
+                    .unwrap_or_else(|_| {
+                        let error = Some("Worker panicked".to_owned());
+                        TaskReport::aborted(task.id.clone(), key, TaskState::Failed, error)
+                    });
+                    match &report.error {
+                        Some(e) => {
+                            tracing::error!("Task {} {}: {}", report.key, report.state.as_str(), e)
+                        }
+                        None => tracing::info!("Task {} {}", report.key, report.state.as_str()),
+                    }
+                    table.finish(report);
+                });
+                workers.push(worker);
+            }
+            for worker in workers {
+                let _ = worker.join();
             }
         });
 
@@ -0,0 +1,131 @@
+// Copyright (c) 2023-2024 Optimatist Technology Co., Ltd. All rights reserved.
+// DO NOT ALTER OR REMOVE COPYRIGHT NOTICES OR THIS FILE HEADER.
+//
+// This file is part of PSH.
+//
+// PSH is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License
+// as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+//
+// PSH is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even
+// the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License along with Performance Savior Home (PSH). If not,
+// see <https://www.gnu.org/licenses/>.
+
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use influxdb_line_protocol::LineProtocolBuilder;
+use nix::sys::resource::{getrusage, UsageWho};
+use nix::sys::time::TimeValLike;
+use psh_proto::{Data, DataType};
+
+use super::TaskState;
+
+/// CPU time consumed by the thread running a task.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Usage {
+    pub user_time: Duration,
+    pub system_time: Duration,
+}
+
+impl Usage {
+    /// Usage of the calling thread since it was created.
+    pub fn thread() -> Self {
+        let Ok(usage) = getrusage(UsageWho::RUSAGE_THREAD) else {
+            return Self::default();
+        };
+        let to_duration = |us: i64| Duration::from_micros(us.max(0) as u64);
+        Self {
+            user_time: to_duration(usage.user_time().num_microseconds()),
+            system_time: to_duration(usage.system_time().num_microseconds()),
+        }
+    }
+
+    pub const fn since(self, earlier: Self) -> Self {
+        Self {
+            user_time: self.user_time.saturating_sub(earlier.user_time),
+            system_time: self.system_time.saturating_sub(earlier.system_time),
+        }
+    }
+}
+
+/// Output captured from a task, see `Task::capture_output`.
+#[derive(Debug, Clone, Default)]
+pub struct TaskOutput {
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+}
+
+/// Outcome of a finished task.
+#[derive(Debug, Clone)]
+pub struct TaskReport {
+    /// Task id assigned by the server, `None` for local tasks.
+    pub id: Option<String>,
+    /// Key of the task in the runtime, equals to `id` if it has one.
+    pub key: String,
+    pub state: TaskState,
+    pub error: Option<String>,
+    /// Exit code returned by `wasi:cli/run` or passed to `exit`.
+    pub exit_code: Option<i32>,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+    pub usage: Usage,
+    pub output: Option<TaskOutput>,
+    /// Exported data discarded or refused because the buffer was full.
+    pub data_overflows: u64,
+}
+
+impl TaskReport {
+    /// Report of a task that did not run to completion, cancelled while queued or lost to a panic.
+    pub fn aborted(
+        id: Option<String>,
+        key: String,
+        state: TaskState,
+        error: Option<String>,
+    ) -> Self {
+        let now = Utc::now();
+        Self {
+            id,
+            key,
+            state,
+            error,
+            exit_code: None,
+            started_at: now,
+            finished_at: now,
+            usage: Usage::default(),
+            output: None,
+            data_overflows: 0,
+        }
+    }
+
+    /// Encode the report as a `psh_task` line protocol record.
+    pub fn to_data(&self, instance_id: &str) -> Data {
+        let nanos = |t: &DateTime<Utc>| t.timestamp_nanos_opt().unwrap_or_default();
+
+        let lp = LineProtocolBuilder::new()
+            .measurement("psh_task")
+            .tag("task_id", &self.key)
+            .tag("instance_id", instance_id)
+            .tag("state", self.state.as_str())
+            .field("started_at", nanos(&self.started_at))
+            .field("finished_at", nanos(&self.finished_at))
+            .field("user_time_us", self.usage.user_time.as_micros() as u64)
+            .field("system_time_us", self.usage.system_time.as_micros() as u64)
+            .field("data_overflows", self.data_overflows);
+        let lp = match self.exit_code {
+            Some(code) => lp.field("exit_code", i64::from(code)),
+            None => lp,
+        };
+        let lp = match &self.error {
+            Some(error) => lp.field("error", error.as_str()),
+            None => lp,
+        };
+        let bytes = lp.timestamp(nanos(&self.finished_at)).close_line().build();
+
+        Data {
+            ty: DataType::LineProtocol as _,
+            bytes,
+        }
+    }
+}
@@ -0,0 +1,96 @@
+// Copyright (c) 2023-2024 Optimatist Technology Co., Ltd. All rights reserved.
+// DO NOT ALTER OR REMOVE COPYRIGHT NOTICES OR THIS FILE HEADER.
+//
+// This file is part of PSH.
+//
+// PSH is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License
+// as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+//
+// PSH is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even
+// the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License along with Performance Savior Home (PSH). If not,
+// see <https://www.gnu.org/licenses/>.
+
+use std::collections::HashSet;
+use std::fs::{self, DirBuilder};
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+use std::sync::{LazyLock, Mutex};
+
+use anyhow::{bail, Context, Result};
+use sha2::{Digest, Sha256};
+
+/// Scratch dirs of the running tasks.
+static OWNED: LazyLock<Mutex<HashSet<PathBuf>>> = LazyLock::new(Default::default);
+
+/// Name of a directory for the task, safe for any key.
+/// The hash of the key tells apart the keys sanitized to the same name, e.g. `a.b` and `a_b`.
+pub fn dir_name(key: &str) -> String {
+    let name: String = key
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' => c,
+            _ => '_',
+        })
+        .collect();
+    let hash = hex::encode(&Sha256::digest(key)[..8]);
+    format!("{}-{}", name, hash)
+}
+
+/// A writable directory of a task, removed with everything in it on drop.
+pub struct ScratchDir {
+    path: PathBuf,
+}
+
+impl ScratchDir {
+    pub fn create(parent: &str, key: &str) -> Result<Self> {
+        let path = Path::new(parent).join(dir_name(key));
+        if !OWNED.lock().unwrap().insert(path.clone()) {
+            bail!("Scratch dir {} is used by a running task", path.display());
+        }
+        // owned from here, so it is released on drop even if creating it fails
+        let dir = Self { path };
+        // left behind if psh was killed while the task was running
+        if dir.path.exists() {
+            fs::remove_dir_all(&dir.path)?;
+        }
+        DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&dir.path)
+            .with_context(|| format!("Failed to create scratch dir {}", dir.path.display()))?;
+        Ok(dir)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_dir_all(&self.path) {
+            if self.path.exists() {
+                tracing::warn!(
+                    "Failed to remove scratch dir {}: {}",
+                    self.path.display(),
+                    e
+                );
+            }
+        }
+        OWNED.lock().unwrap().remove(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dir_name() {
+        assert!(dir_name("local-0").starts_with("local-0-"));
+        assert_ne!(dir_name("a.b"), dir_name("a_b"));
+        assert!(!dir_name("../x").contains('/'));
+    }
+}
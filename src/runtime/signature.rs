@@ -0,0 +1,247 @@
+// Copyright (c) 2023-2024 Optimatist Technology Co., Ltd. All rights reserved.
+// DO NOT ALTER OR REMOVE COPYRIGHT NOTICES OR THIS FILE HEADER.
+//
+// This file is part of PSH.
+//
+// PSH is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License
+// as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+//
+// PSH is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even
+// the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License along with Performance Savior Home (PSH). If not,
+// see <https://www.gnu.org/licenses/>.
+
+//! Ed25519 signatures of components.
+//!
+//! A signature is either detached, stored base64 encoded next to the component in
+//! `<path>.sig`, or embedded as the last custom section of the component, named
+//! [`SECTION`], signing every byte before that section.
+
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use ed25519_dalek::{Signature, SigningKey, VerifyingKey};
+
+use crate::config::{SignatureConfig, SignaturePolicy};
+
+pub const SECTION: &str = "psh-signature";
+
+/// A component without a signature made by a trusted key, under the `require` policy.
+#[derive(Debug)]
+pub struct UntrustedComponent(pub String);
+
+impl fmt::Display for UntrustedComponent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Untrusted component: {}", self.0)
+    }
+}
+
+impl std::error::Error for UntrustedComponent {}
+
+pub struct Verifier {
+    policy: SignaturePolicy,
+    keys: Vec<VerifyingKey>,
+}
+
+impl Verifier {
+    pub fn new(cfg: &SignatureConfig) -> Result<Self> {
+        let keys = cfg
+            .trusted_keys
+            .iter()
+            .map(|it| {
+                let bytes = BASE64_STANDARD.decode(it)?;
+                let bytes = bytes
+                    .try_into()
+                    .map_err(|_| anyhow::anyhow!("not 32 bytes"))?;
+                Ok(VerifyingKey::from_bytes(&bytes)?)
+            })
+            .collect::<Result<Vec<_>>>()
+            .context("Invalid trusted key")?;
+        if cfg.policy != SignaturePolicy::Off && keys.is_empty() {
+            bail!("No trusted keys configured for signature verification");
+        }
+        Ok(Self {
+            policy: cfg.policy,
+            keys,
+        })
+    }
+
+    /// Apply the policy to a component, `detached` is its detached signature if any.
+    pub fn check(&self, binary: &[u8], detached: Option<&[u8]>) -> Result<()> {
+        if self.policy == SignaturePolicy::Off {
+            return Ok(());
+        }
+        match self.verify(binary, detached) {
+            Ok(()) => Ok(()),
+            Err(e) if self.policy == SignaturePolicy::Warn => {
+                tracing::warn!("Running untrusted component: {e}");
+                Ok(())
+            }
+            Err(e) => Err(UntrustedComponent(e).into()),
+        }
+    }
+
+    fn verify(&self, binary: &[u8], detached: Option<&[u8]>) -> Result<(), String> {
+        let (signed, signature) = match detached {
+            Some(signature) => (binary, signature),
+            None => embedded(binary).ok_or("component is not signed")?,
+        };
+        let signature = Signature::from_slice(signature).map_err(|e| e.to_string())?;
+        if self
+            .keys
+            .iter()
+            .any(|key| key.verify_strict(signed, &signature).is_ok())
+        {
+            return Ok(());
+        }
+        Err("signature is not made by a trusted key".to_owned())
+    }
+}
+
+/// Read the detached signature of the component at `path`, if there is one.
+pub fn read_detached(path: &str) -> Result<Option<Vec<u8>>> {
+    let path = format!("{path}.sig");
+    if !Path::new(&path).exists() {
+        return Ok(None);
+    }
+    let signature = fs::read_to_string(&path)?;
+    let signature = BASE64_STANDARD
+        .decode(signature.trim())
+        .with_context(|| format!("Invalid signature {path}"))?;
+    Ok(Some(signature))
+}
+
+/// Sign a component, returns the component with the signature embedded if `embed`,
+/// otherwise the base64 encoded detached signature.
+pub fn sign(key: &SigningKey, binary: &[u8], embed: bool) -> Vec<u8> {
+    use ed25519_dalek::Signer;
+
+    if !embed {
+        let signature = key.sign(binary).to_bytes();
+        return format!("{}\n", BASE64_STANDARD.encode(signature)).into_bytes();
+    }
+
+    // Replace the embedded signature of a signed component.
+    let binary = embedded(binary).map_or(binary, |(signed, _)| signed);
+    let signature = key.sign(binary).to_bytes();
+
+    let mut payload = vec![];
+    write_leb128(&mut payload, SECTION.len());
+    payload.extend_from_slice(SECTION.as_bytes());
+    payload.extend_from_slice(&signature);
+
+    let mut signed = binary.to_vec();
+    signed.push(0); // custom section
+    write_leb128(&mut signed, payload.len());
+    signed.extend_from_slice(&payload);
+    signed
+}
+
+/// Split a component into the signed bytes and the signature of its last section.
+fn embedded(binary: &[u8]) -> Option<(&[u8], &[u8])> {
+    if !binary.starts_with(b"\0asm") || binary.len() < 8 {
+        return None;
+    }
+
+    let mut pos = 8;
+    let mut last = None;
+    while pos < binary.len() {
+        let start = pos;
+        let id = binary[pos];
+        let (size, n) = read_leb128(&binary[pos + 1..])?;
+        let payload = pos + 1 + n;
+        pos = payload.checked_add(size).filter(|it| *it <= binary.len())?;
+        last = Some((start, id, payload));
+    }
+
+    let (start, id, payload) = last?;
+    if id != 0 {
+        return None;
+    }
+    let payload = &binary[payload..];
+    let (len, n) = read_leb128(payload)?;
+    let name = payload.get(n..n + len)?;
+    if name != SECTION.as_bytes() {
+        return None;
+    }
+    Some((&binary[..start], &payload[n + len..]))
+}
+
+fn read_leb128(bytes: &[u8]) -> Option<(usize, usize)> {
+    let mut value = 0usize;
+    for (i, byte) in bytes.iter().take(5).enumerate() {
+        value |= usize::from(byte & 0x7f) << (i * 7);
+        if byte & 0x80 == 0 {
+            return Some((value, i + 1));
+        }
+    }
+    None
+}
+
+fn write_leb128(buf: &mut Vec<u8>, mut value: usize) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            buf.push(byte);
+            return;
+        }
+        buf.push(byte | 0x80);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Header of an empty component, followed by one custom section.
+    const COMPONENT: &[u8] = b"\0asm\x0d\0\x01\0\0\x04\x03foo";
+
+    fn trusting(key: &SigningKey) -> Verifier {
+        let cfg = SignatureConfig {
+            policy: SignaturePolicy::Require,
+            trusted_keys: vec![BASE64_STANDARD.encode(key.verifying_key().as_bytes())],
+        };
+        Verifier::new(&cfg).unwrap()
+    }
+
+    #[test]
+    fn test_embedded() {
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let verifier = trusting(&key);
+
+        let signed = sign(&key, COMPONENT, true);
+        assert!(signed.starts_with(COMPONENT));
+        verifier.check(&signed, None).unwrap();
+        // signing again replaces the signature
+        assert_eq!(sign(&key, &signed, true), signed);
+
+        let mut tampered = signed.clone();
+        tampered[9] ^= 1;
+        assert!(verifier.check(&tampered, None).is_err());
+        assert!(verifier.check(COMPONENT, None).is_err());
+
+        let other = trusting(&SigningKey::from_bytes(&[8; 32]));
+        assert!(other.check(&signed, None).is_err());
+    }
+
+    #[test]
+    fn test_detached() {
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let verifier = trusting(&key);
+
+        let signature = sign(&key, COMPONENT, false);
+        let signature = BASE64_STANDARD
+            .decode(std::str::from_utf8(&signature).unwrap().trim())
+            .unwrap();
+        verifier.check(COMPONENT, Some(&signature)).unwrap();
+
+        let mut tampered = COMPONENT.to_vec();
+        tampered[9] ^= 1;
+        assert!(verifier.check(&tampered, Some(&signature)).is_err());
+    }
+}
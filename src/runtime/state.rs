@@ -15,6 +15,7 @@
 use host_op_perf::PerfCtx;
 use host_op_system::SysCtx;
 use wasmtime::component::ResourceTable;
+use wasmtime::StoreLimits;
 use wasmtime_wasi::{WasiCtx, WasiView};
 
 use super::DataExportCtx;
@@ -27,6 +28,7 @@ pub struct PshState {
     pub perf_ctx: PerfCtx,
     pub sys_ctx: SysCtx,
     pub data_export_ctx: DataExportCtx,
+    pub limits: StoreLimits,
     // TODO: add more context for modules
 }
 
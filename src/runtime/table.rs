@@ -0,0 +1,210 @@
+// Copyright (c) 2023-2024 Optimatist Technology Co., Ltd. All rights reserved.
+// DO NOT ALTER OR REMOVE COPYRIGHT NOTICES OR THIS FILE HEADER.
+//
+// This file is part of PSH.
+//
+// PSH is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License
+// as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+//
+// PSH is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even
+// the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License along with Performance Savior Home (PSH). If not,
+// see <https://www.gnu.org/licenses/>.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Condvar, Mutex};
+
+use anyhow::{bail, Result};
+
+use super::{Interrupter, TaskReport};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskState {
+    Queued,
+    Instantiating,
+    Running,
+    Succeeded,
+    Failed,
+    TimedOut,
+    Cancelled,
+    /// The component was refused before running, being incompatible or untrusted.
+    Rejected,
+}
+
+impl TaskState {
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            Self::Queued => "queued",
+            Self::Instantiating => "instantiating",
+            Self::Running => "running",
+            Self::Succeeded => "succeeded",
+            Self::Failed => "failed",
+            Self::TimedOut => "timed-out",
+            Self::Cancelled => "cancelled",
+            Self::Rejected => "rejected",
+        }
+    }
+
+    /// The task holds a worker slot in this state.
+    pub const fn is_active(&self) -> bool {
+        matches!(self, Self::Instantiating | Self::Running)
+    }
+}
+
+/// Number of finished tasks kept for inspection.
+const MAX_HISTORY: usize = 32;
+/// Number of reports of server tasks kept until they are taken, the oldest are dropped beyond it.
+const MAX_FINISHED: usize = 1024;
+
+struct Entry {
+    state: TaskState,
+    cancelled: bool,
+    interrupter: Option<Interrupter>,
+}
+
+/// Tracks every task owned by a `TaskRuntime` until its report is taken.
+#[derive(Default)]
+pub struct TaskTable {
+    tasks: Mutex<HashMap<String, Entry>>,
+    finished: Mutex<VecDeque<TaskReport>>,
+    history: Mutex<VecDeque<TaskReport>>,
+    slot_freed: Condvar,
+    local_id: AtomicU64,
+}
+
+impl TaskTable {
+    /// Add a queued task, tasks without an id get a local one.
+    /// Fails if a task with the same id is still queued or active.
+    pub fn insert(&self, id: Option<String>) -> Result<String> {
+        let key = id.unwrap_or_else(|| {
+            let n = self.local_id.fetch_add(1, Ordering::Relaxed);
+            format!("local-{}", n)
+        });
+        let mut tasks = self.tasks.lock().unwrap();
+        if tasks.contains_key(&key) {
+            bail!("Task {} is already queued or running", key);
+        }
+        let entry = Entry {
+            state: TaskState::Queued,
+            cancelled: false,
+            interrupter: None,
+        };
+        tasks.insert(key.clone(), entry);
+        drop(tasks);
+        Ok(key)
+    }
+
+    /// Block until less than `max_active` tasks are active, then mark `key` as instantiating.
+    /// Returns `false` if the task was cancelled while queued.
+    pub fn start(&self, key: &str, max_active: usize) -> bool {
+        let mut tasks = self.tasks.lock().unwrap();
+        if tasks.get(key).is_some_and(|it| it.cancelled) {
+            return false;
+        }
+        while tasks.values().filter(|it| it.state.is_active()).count() >= max_active {
+            tasks = self.slot_freed.wait(tasks).unwrap();
+        }
+        match tasks.get_mut(key) {
+            Some(it) if !it.cancelled => {
+                it.state = TaskState::Instantiating;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn set_state(&self, key: &str, state: TaskState) {
+        if let Some(it) = self.tasks.lock().unwrap().get_mut(key) {
+            it.state = state;
+        }
+    }
+
+    /// Register the interrupter of a started task, it fires at once if the task was cancelled.
+    pub fn set_interrupter(&self, key: &str, interrupter: Interrupter) {
+        if let Some(it) = self.tasks.lock().unwrap().get_mut(key) {
+            if it.cancelled {
+                interrupter.interrupt();
+            }
+            it.interrupter = Some(interrupter);
+        }
+    }
+
+    /// Cancel a queued or active task, returns `false` if there is no such task.
+    pub fn cancel(&self, key: &str) -> bool {
+        if let Some(it) = self.tasks.lock().unwrap().get_mut(key) {
+            it.cancelled = true;
+            if let Some(interrupter) = &it.interrupter {
+                interrupter.interrupt();
+            }
+            return true;
+        }
+        false
+    }
+
+    pub fn cancel_all(&self) {
+        for it in self.tasks.lock().unwrap().values_mut() {
+            it.cancelled = true;
+            if let Some(interrupter) = &it.interrupter {
+                interrupter.interrupt();
+            }
+        }
+    }
+
+    pub fn is_cancelled(&self, key: &str) -> bool {
+        let tasks = self.tasks.lock().unwrap();
+        tasks.get(key).is_some_and(|it| it.cancelled)
+    }
+
+    /// Remove a finished task and queue its report if it came from the server,
+    /// nothing takes the reports of local tasks.
+    pub fn finish(&self, report: TaskReport) {
+        self.tasks.lock().unwrap().remove(&report.key);
+        {
+            let mut history = self.history.lock().unwrap();
+            if history.len() == MAX_HISTORY {
+                history.pop_front();
+            }
+            history.push_back(report.clone());
+        }
+        if report.id.is_some() {
+            let mut finished = self.finished.lock().unwrap();
+            if finished.len() == MAX_FINISHED {
+                if let Some(dropped) = finished.pop_front() {
+                    tracing::warn!("Dropped the report of task {}, not taken", dropped.key);
+                }
+            }
+            // the output is only kept in the history
+            finished.push_back(TaskReport {
+                output: None,
+                ..report
+            });
+        }
+        self.slot_freed.notify_all();
+    }
+
+    /// Take reports of all server tasks finished since the last call, oldest first.
+    pub fn take_finished(&self) -> Vec<TaskReport> {
+        self.finished.lock().unwrap().drain(..).collect()
+    }
+
+    /// Queued and active tasks with their states.
+    pub fn states(&self) -> Vec<(String, TaskState)> {
+        let tasks = self.tasks.lock().unwrap();
+        tasks.iter().map(|(k, v)| (k.clone(), v.state)).collect()
+    }
+
+    /// Reports of the most recently finished tasks, oldest first.
+    pub fn history(&self) -> Vec<TaskReport> {
+        self.history.lock().unwrap().iter().cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.tasks.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
@@ -0,0 +1,293 @@
+// Copyright (c) 2023-2024 Optimatist Technology Co., Ltd. All rights reserved.
+// DO NOT ALTER OR REMOVE COPYRIGHT NOTICES OR THIS FILE HEADER.
+//
+// This file is part of PSH.
+//
+// PSH is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License
+// as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+//
+// PSH is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even
+// the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License along with Performance Savior Home (PSH). If not,
+// see <https://www.gnu.org/licenses/>.
+
+//! Local control socket.
+//!
+//! Clients send one JSON request per line and get one JSON response per line,
+//! every response has an `ok` field and an `error` message when it is `false`.
+//!
+//! - `{"cmd": "submit", "path": "/path/to/your.wasm", "args": ["foo"]}` queues a task,
+//!   `wasm` (base64 encoded component) can be given instead of `path`, optional
+//!   `signature` (base64 encoded detached signature of `wasm`, read from `<path>.sig` for `path`),
+//!   `end_time` (in milliseconds since the epoch), `envs` (object of variables),
+//!   `limits` (object like `[runtime.limits]`, missing fields take their defaults) and
+//!   `preopens` (array like `runtime.fs.preopens`) overriding the config.
+//!   Responds with the `key` of the task.
+//! - `{"cmd": "status"}` responds with `pid`, `version`, the number of `queued` and `running` tasks,
+//!   `spool` counters in bytes (`pending`, `spooled`, `replayed`, `dropped`) and `export`
+//!   statistics (`raw_bytes`, `sent_bytes` after compression, `ratio`), null if disabled.
+//! - `{"cmd": "list"}` responds with `tasks`, queued, running and recently finished.
+//! - `{"cmd": "cancel", "key": "local-0"}` cancels a queued or running task.
+//! - `{"cmd": "output", "key": "local-0"}` responds with `stdout` and `stderr` of a finished task.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{BufRead, Write};
+use std::os::unix::fs::DirBuilderExt;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{bail, Context, Result};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use chrono::{offset::LocalResult, TimeZone, Utc};
+use nix::sys::stat::{umask, Mode};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::watch;
+
+use crate::config::{ControlConfig, LimitsConfig, PreopenConfig};
+use crate::runtime::{signature, Task, TaskReport, TaskRuntime, TaskState};
+use crate::services::{rpc::ExportCounters, spool::Spool};
+
+#[derive(Deserialize)]
+#[serde(tag = "cmd", rename_all = "kebab-case")]
+enum Request {
+    Submit {
+        path: Option<String>,
+        wasm: Option<String>,
+        signature: Option<String>,
+        #[serde(default)]
+        args: Vec<String>,
+        end_time: Option<i64>,
+        #[serde(default)]
+        envs: BTreeMap<String, String>,
+        limits: Option<LimitsConfig>,
+        preopens: Option<Vec<PreopenConfig>>,
+    },
+    Status,
+    List,
+    Cancel {
+        key: String,
+    },
+    Output {
+        key: String,
+    },
+}
+
+/// Serve the control socket until `shutdown` turns `true`.
+pub async fn serve(
+    cfg: ControlConfig,
+    task_rt: Arc<TaskRuntime>,
+    spool: Option<Arc<Spool>>,
+    export_counters: Option<Arc<ExportCounters>>,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<()> {
+    let path = Path::new(&cfg.path);
+    if let Some(parent) = path.parent() {
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(parent)?;
+    }
+    // The socket is created with the umask, so it is never accessible by others.
+    // Bind to a temporary path and rename it to replace a stale socket atomically.
+    let tmp = format!("{}.{}", cfg.path, std::process::id());
+    let _ = fs::remove_file(&tmp);
+    let old_umask = umask(Mode::from_bits_truncate(0o177));
+    let listener = UnixListener::bind(&tmp);
+    umask(old_umask);
+    let listener =
+        listener.with_context(|| format!("Failed to bind control socket {}", cfg.path))?;
+    fs::rename(&tmp, path)?;
+
+    loop {
+        tokio::select! {
+            res = listener.accept() => match res {
+                Ok((stream, _)) => {
+                    let task_rt = task_rt.clone();
+                    let spool = spool.clone();
+                    let export_counters = export_counters.clone();
+                    tokio::spawn(async move {
+                        let stats = Stats {
+                            spool: spool.as_deref(),
+                            export: export_counters.as_deref(),
+                        };
+                        if let Err(e) = handle(stream, &task_rt, stats, cfg.max_output).await {
+                            tracing::warn!("Control connection: {e}");
+                        }
+                    });
+                }
+                Err(e) => tracing::warn!("Failed to accept control connection: {e}"),
+            },
+            _ = shutdown.wait_for(|it| *it) => break,
+        }
+    }
+
+    let _ = fs::remove_file(path);
+    Ok(())
+}
+
+/// Statistics of the rpc services, `None` if disabled.
+#[derive(Clone, Copy)]
+struct Stats<'a> {
+    spool: Option<&'a Spool>,
+    export: Option<&'a ExportCounters>,
+}
+
+async fn handle(
+    stream: UnixStream,
+    task_rt: &TaskRuntime,
+    stats: Stats<'_>,
+    max_output: usize,
+) -> Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        let resp = match serde_json::from_str::<Request>(&line) {
+            Ok(req) => handle_request(req, task_rt, stats, max_output).await,
+            Err(e) => Err(e.into()),
+        };
+        let resp = resp.unwrap_or_else(|e| json!({ "ok": false, "error": format!("{e:#}") }));
+        writer.write_all(format!("{resp}\n").as_bytes()).await?;
+    }
+    Ok(())
+}
+
+async fn handle_request(
+    req: Request,
+    task_rt: &TaskRuntime,
+    stats: Stats<'_>,
+    max_output: usize,
+) -> Result<Value> {
+    let resp = match req {
+        Request::Submit {
+            path,
+            wasm,
+            signature,
+            args,
+            end_time,
+            envs,
+            limits,
+            preopens,
+        } => {
+            let (name, wasm_component, signature) = match (path, wasm) {
+                (Some(path), None) => {
+                    let (wasm, signature) = {
+                        let path = path.clone();
+                        tokio::task::spawn_blocking(move || -> Result<_> {
+                            let wasm = fs::read(&path)
+                                .with_context(|| format!("Failed to read {path}"))?;
+                            Ok((wasm, signature::read_detached(&path)?))
+                        })
+                        .await??
+                    };
+                    (path, wasm, signature)
+                }
+                (None, Some(wasm)) => {
+                    let signature = signature.map(|it| BASE64_STANDARD.decode(it)).transpose()?;
+                    ("wasm".to_owned(), BASE64_STANDARD.decode(wasm)?, signature)
+                }
+                _ => bail!("Either path or wasm is required"),
+            };
+            let end_time = match end_time {
+                Some(ms) => match Utc.timestamp_millis_opt(ms) {
+                    LocalResult::Single(t) => t,
+                    _ => bail!("Invalid task end time"),
+                },
+                None => Utc.with_ymd_and_hms(3000, 1, 1, 1, 1, 1).unwrap(),
+            };
+            let task = Task {
+                id: None,
+                wasm_component,
+                signature,
+                wasm_component_args: std::iter::once(name).chain(args).collect(),
+                end_time,
+                envs: envs.into_iter().collect(),
+                limits,
+                preopens,
+                allow_precompiled: false,
+                capture_output: Some(max_output),
+            };
+            let key = task_rt.schedule(task)?;
+            json!({ "ok": true, "key": key })
+        }
+        Request::Status => {
+            let states = task_rt.active_tasks();
+            let queued = states
+                .iter()
+                .filter(|(_, state)| *state == TaskState::Queued)
+                .count();
+            json!({
+                "ok": true,
+                "pid": std::process::id(),
+                "version": env!("CARGO_PKG_VERSION"),
+                "queued": queued,
+                "running": states.len() - queued,
+                "spool": stats.spool.map(Spool::stats),
+                "export": stats.export.map(ExportCounters::stats),
+            })
+        }
+        Request::List => {
+            let finished = task_rt.recent_tasks();
+            let finished = finished.iter().map(report_json);
+            let active = task_rt
+                .active_tasks()
+                .into_iter()
+                .map(|(key, state)| json!({ "key": key, "state": state.as_str() }));
+            json!({ "ok": true, "tasks": finished.chain(active).collect::<Vec<_>>() })
+        }
+        Request::Cancel { key } => {
+            if !task_rt.cancel(&key) {
+                bail!("No such queued or running task");
+            }
+            json!({ "ok": true })
+        }
+        Request::Output { key } => {
+            let Some(report) = task_rt.recent_tasks().into_iter().rfind(|it| it.key == key) else {
+                bail!("No such finished task");
+            };
+            let Some(output) = report.output else {
+                bail!("Output of the task was not captured");
+            };
+            json!({
+                "ok": true,
+                "stdout": String::from_utf8_lossy(&output.stdout),
+                "stderr": String::from_utf8_lossy(&output.stderr),
+            })
+        }
+    };
+    Ok(resp)
+}
+
+/// Send a request to the control socket of a running psh.
+pub fn call(path: &str, req: &Value) -> Result<Value> {
+    let mut stream = std::os::unix::net::UnixStream::connect(path).with_context(|| {
+        format!("Failed to connect to {path}, is psh running with control enabled?")
+    })?;
+    writeln!(stream, "{req}")?;
+    let mut line = String::new();
+    std::io::BufReader::new(stream).read_line(&mut line)?;
+    let resp: Value = serde_json::from_str(&line)?;
+    if resp["ok"] != true {
+        bail!("{}", resp["error"].as_str().unwrap_or("Unknown error"));
+    }
+    Ok(resp)
+}
+
+fn report_json(report: &TaskReport) -> Value {
+    json!({
+        "key": report.key,
+        "id": report.id,
+        "state": report.state.as_str(),
+        "error": report.error,
+        "exit_code": report.exit_code,
+        "started_at": report.started_at.to_rfc3339(),
+        "finished_at": report.finished_at.to_rfc3339(),
+        "user_time_us": report.usage.user_time.as_micros() as u64,
+        "system_time_us": report.usage.system_time.as_micros() as u64,
+        "data_overflows": report.data_overflows,
+    })
+}
@@ -0,0 +1,160 @@
+// Copyright (c) 2023-2024 Optimatist Technology Co., Ltd. All rights reserved.
+// DO NOT ALTER OR REMOVE COPYRIGHT NOTICES OR THIS FILE HEADER.
+//
+// This file is part of PSH.
+//
+// PSH is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License
+// as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+//
+// PSH is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even
+// the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License along with Performance Savior Home (PSH). If not,
+// see <https://www.gnu.org/licenses/>.
+
+use std::io::Write;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use flate2::{write::GzEncoder, Compression};
+use reqwest::header::{AUTHORIZATION, CONTENT_ENCODING, CONTENT_TYPE};
+use reqwest::{Client, StatusCode, Url};
+
+use crate::config::{InfluxdbConfig, Precision};
+use crate::services::rpc::ExportCounters;
+use psh_proto::{DataType, ExportDataReq};
+
+/// Attempts of a write failing with a server or transport error, before giving up.
+const MAX_ATTEMPTS: u32 = 3;
+/// Delay before the second attempt, doubled for each next one.
+const RETRY_BACKOFF: Duration = Duration::from_millis(500);
+
+/// Writes line protocol to the `/api/v2/write` endpoint of InfluxDB.
+#[derive(Clone)]
+pub struct InfluxClient {
+    client: Client,
+    url: Url,
+    token: String,
+    gzip: bool,
+    precision: Precision,
+    counters: Arc<ExportCounters>,
+}
+
+impl InfluxClient {
+    pub fn new(config: &InfluxdbConfig, counters: Arc<ExportCounters>) -> Result<Self> {
+        let mut url = Url::parse(&format!(
+            "{}/api/v2/write",
+            config.url.trim_end_matches('/')
+        ))?;
+        url.query_pairs_mut()
+            .append_pair("org", &config.org)
+            .append_pair("bucket", &config.bucket)
+            .append_pair("precision", config.precision.as_str());
+        let client = Client::builder()
+            .timeout(Duration::from_secs(config.timeout))
+            .build()?;
+
+        Ok(Self {
+            client,
+            url,
+            token: config.token.clone(),
+            gzip: config.gzip,
+            precision: config.precision,
+            counters,
+        })
+    }
+
+    pub const fn precision(&self) -> Precision {
+        self.precision
+    }
+
+    /// Write the line protocol of the request, other data is discarded.
+    /// Data refused by InfluxDB as invalid is discarded too, so it does not block the spool.
+    /// Server and transport errors are retried `MAX_ATTEMPTS` times before failing.
+    pub async fn write(&self, req: &ExportDataReq) -> Result<()> {
+        let (lines, others): (Vec<_>, Vec<_>) = req
+            .data
+            .iter()
+            .partition(|it| it.ty == DataType::LineProtocol as i32);
+        if !others.is_empty() {
+            tracing::warn!(
+                "InfluxDB only takes line protocol, discarded {} files of task {}",
+                others.len(),
+                req.task_id
+            );
+        }
+        if lines.is_empty() {
+            return Ok(());
+        }
+
+        let body: Vec<u8> = lines.into_iter().flat_map(|it| it.bytes.clone()).collect();
+        let raw = body.len() as u64;
+        let body = if self.gzip {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(&body)?;
+            encoder.finish()?
+        } else {
+            body
+        };
+        let sent = body.len() as u64;
+
+        let mut attempt = 1;
+        let mut backoff = RETRY_BACKOFF;
+        let resp = loop {
+            let error = match self.post(body.clone()).await {
+                Ok(resp) if !resp.status().is_server_error() => break resp,
+                Ok(resp) => {
+                    let status = resp.status();
+                    let message = resp.text().await.unwrap_or_default();
+                    anyhow!("InfluxDB responded {}: {}", status, message)
+                }
+                Err(e) => e.into(),
+            };
+            if attempt == MAX_ATTEMPTS {
+                return Err(error);
+            }
+            tracing::warn!(
+                "Failed to write data of task {} to InfluxDB, retrying in {}ms: {}",
+                req.task_id,
+                backoff.as_millis(),
+                error
+            );
+            tokio::time::sleep(backoff).await;
+            attempt += 1;
+            backoff *= 2;
+        };
+        let status = resp.status();
+        if status.is_success() {
+            self.counters.add(raw, sent);
+            return Ok(());
+        }
+        let message = resp.text().await.unwrap_or_default();
+        match status {
+            StatusCode::BAD_REQUEST
+            | StatusCode::PAYLOAD_TOO_LARGE
+            | StatusCode::UNPROCESSABLE_ENTITY => {
+                tracing::error!(
+                    "InfluxDB refused data of task {}, discarded it: {} {}",
+                    req.task_id,
+                    status,
+                    message
+                );
+                Ok(())
+            }
+            _ => bail!("InfluxDB responded {}: {}", status, message),
+        }
+    }
+
+    async fn post(&self, body: Vec<u8>) -> reqwest::Result<reqwest::Response> {
+        let mut request = self
+            .client
+            .post(self.url.clone())
+            .header(AUTHORIZATION, format!("Token {}", self.token))
+            .header(CONTENT_TYPE, "text/plain; charset=utf-8");
+        if self.gzip {
+            request = request.header(CONTENT_ENCODING, "gzip");
+        }
+        request.body(body).send().await
+    }
+}
@@ -12,5 +12,9 @@
 // You should have received a copy of the GNU Lesser General Public License along with Performance Savior Home (PSH). If not,
 // see <https://www.gnu.org/licenses/>.
 
+pub mod control;
 pub mod host_info;
+pub mod influxdb;
+pub mod prometheus;
 pub mod rpc;
+pub mod spool;
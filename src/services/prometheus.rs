@@ -0,0 +1,104 @@
+// Copyright (c) 2023-2024 Optimatist Technology Co., Ltd. All rights reserved.
+// DO NOT ALTER OR REMOVE COPYRIGHT NOTICES OR THIS FILE HEADER.
+//
+// This file is part of PSH.
+//
+// PSH is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License
+// as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+//
+// PSH is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even
+// the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License along with Performance Savior Home (PSH). If not,
+// see <https://www.gnu.org/licenses/>.
+
+//! HTTP endpoint for Prometheus to scrape, only `GET /metrics` is served.
+
+use std::convert::Infallible;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use http_body_util::Full;
+use hyper::body::{Bytes, Incoming};
+use hyper::header::CONTENT_TYPE;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::{TokioIo, TokioTimer};
+use prometheus::TEXT_FORMAT;
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+
+use crate::config::PrometheusConfig;
+use crate::otlp::PrometheusRegistry;
+
+/// in bytes, requests with larger headers are refused
+const MAX_HEAD: usize = 8 << 10;
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Serve the metrics until `shutdown` turns `true`.
+pub async fn serve(
+    cfg: PrometheusConfig,
+    registry: PrometheusRegistry,
+    mut shutdown: watch::Receiver<bool>,
+) -> Result<()> {
+    let listener = TcpListener::bind(cfg.listen)
+        .await
+        .with_context(|| format!("Failed to bind prometheus endpoint {}", cfg.listen))?;
+    tracing::info!(
+        "Serving prometheus metrics on http://{}/metrics",
+        cfg.listen
+    );
+
+    loop {
+        tokio::select! {
+            res = listener.accept() => match res {
+                Ok((stream, _)) => {
+                    let registry = registry.clone();
+                    let service = service_fn(move |req| handle(req, registry.clone()));
+                    let conn = http1::Builder::new()
+                        .timer(TokioTimer::new())
+                        .header_read_timeout(READ_TIMEOUT)
+                        .max_buf_size(MAX_HEAD)
+                        .serve_connection(TokioIo::new(stream), service);
+                    tokio::spawn(async move {
+                        if let Err(e) = conn.await {
+                            tracing::debug!("Prometheus connection: {e}");
+                        }
+                    });
+                }
+                Err(e) => tracing::warn!("Failed to accept prometheus connection: {e}"),
+            },
+            _ = shutdown.wait_for(|it| *it) => break,
+        }
+    }
+    Ok(())
+}
+
+async fn handle(
+    req: Request<Incoming>,
+    registry: PrometheusRegistry,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    let (status, body) = match (req.method(), req.uri().path()) {
+        (&Method::GET, "/metrics") => {
+            // the callbacks of the gauges read procfs
+            let res = tokio::task::spawn_blocking(move || registry.render()).await;
+            match res.map_err(anyhow::Error::from).and_then(|it| it) {
+                Ok(body) => (StatusCode::OK, body),
+                Err(e) => {
+                    tracing::warn!("Failed to collect metrics: {e}");
+                    (StatusCode::INTERNAL_SERVER_ERROR, String::new())
+                }
+            }
+        }
+        (&Method::GET, _) => (StatusCode::NOT_FOUND, String::new()),
+        _ => (StatusCode::METHOD_NOT_ALLOWED, String::new()),
+    };
+    let mut resp = Response::new(Full::new(Bytes::from(body)));
+    *resp.status_mut() = status;
+    resp.headers_mut().insert(
+        CONTENT_TYPE,
+        TEXT_FORMAT.parse().expect("valid header value"),
+    );
+    Ok(resp)
+}
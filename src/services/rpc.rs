@@ -12,14 +12,31 @@
 // You should have received a copy of the GNU Lesser General Public License along with Performance Savior Home (PSH). If not,
 // see <https://www.gnu.org/licenses/>.
 
-use anyhow::{bail, Result};
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use anyhow::{anyhow, bail, Context as _, Result};
 use chrono::{offset::LocalResult, TimeZone, Utc};
+use http_body_util::BodyExt;
+use prost::Message;
+use serde::{de::DeserializeOwned, Serialize};
 use tonic::{
+    body::BoxBody,
+    codec::CompressionEncoding,
+    codegen::{http, Service},
+    metadata::MetadataMap,
     transport::{Channel, ClientTlsConfig, Endpoint},
-    Request,
+    Code, Request, Response, Status,
 };
 
-use crate::{config::RpcConfig, runtime::Task, services::host_info::new_info_req};
+use crate::{
+    config::{Compression, LimitsConfig, PreopenConfig, RpcConfig},
+    runtime::{Task, TaskReport, TaskState},
+    services::{host_info::new_info_req, spool::Spool},
+};
 use psh_proto::{
     psh_service_client::PshServiceClient, ExportDataReq, GetTaskReq, HeartbeatReq, TaskDoneReq,
     Unit,
@@ -28,7 +45,123 @@ use psh_proto::{
 #[derive(Clone)]
 pub struct RpcClient {
     token: String,
-    client: PshServiceClient<Channel>,
+    client: PshServiceClient<Metered>,
+    compression: Option<Compressed>,
+    counters: Arc<ExportCounters>,
+}
+
+/// Client sending compressed requests, used while the server accepts the encoding.
+#[derive(Clone)]
+struct Compressed {
+    client: PshServiceClient<Metered>,
+    name: &'static str,
+    accepted: Arc<AtomicBool>,
+}
+
+/// Bytes of the exported data, before and after compression.
+#[derive(Default)]
+pub struct ExportCounters {
+    raw: AtomicU64,
+    sent: AtomicU64,
+}
+
+#[derive(Serialize)]
+pub struct ExportStats {
+    pub raw_bytes: u64,
+    /// request bodies as sent, after compression
+    pub sent_bytes: u64,
+    /// `raw_bytes / sent_bytes`
+    pub ratio: f64,
+}
+
+impl ExportCounters {
+    pub fn add(&self, raw: u64, sent: u64) {
+        self.raw.fetch_add(raw, Ordering::Relaxed);
+        self.sent.fetch_add(sent, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> ExportStats {
+        let raw_bytes = self.raw.load(Ordering::Relaxed);
+        let sent_bytes = self.sent.load(Ordering::Relaxed);
+        ExportStats {
+            raw_bytes,
+            sent_bytes,
+            ratio: if sent_bytes == 0 {
+                1.0
+            } else {
+                raw_bytes as f64 / sent_bytes as f64
+            },
+        }
+    }
+}
+
+/// Request extension to count the bytes of its body as sent.
+#[derive(Clone, Default)]
+struct SentBytes(Arc<AtomicU64>);
+
+/// Channel counting the bytes of request bodies with `SentBytes`.
+#[derive(Clone)]
+pub struct Metered(Channel);
+
+impl Service<http::Request<BoxBody>> for Metered {
+    type Response = <Channel as Service<http::Request<BoxBody>>>::Response;
+    type Error = <Channel as Service<http::Request<BoxBody>>>::Error;
+    type Future = <Channel as Service<http::Request<BoxBody>>>::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.0.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: http::Request<BoxBody>) -> Self::Future {
+        let Some(SentBytes(sent)) = req.extensions().get::<SentBytes>().cloned() else {
+            return self.0.call(req);
+        };
+        self.0.call(req.map(|body| {
+            body.map_frame(move |frame| {
+                if let Some(data) = frame.data_ref() {
+                    sent.fetch_add(data.len() as u64, Ordering::Relaxed);
+                }
+                frame
+            })
+            .boxed_unsync()
+        }))
+    }
+}
+
+/// Response metadata of a heartbeat listing the ids of the tasks to cancel, separated by commas.
+/// psh-proto has no field for it in `HeartbeatResp`.
+const CANCEL_KEY: &str = "psh-cancel";
+
+/// Settings of a task in the metadata of a `GetTask` response, as JSON.
+/// psh-proto has no fields for them in `GetTaskResp`.
+struct TaskSettings {
+    /// overrides `runtime.limits`
+    limits: Option<LimitsConfig>,
+    /// overrides `runtime.fs.preopens`
+    preopens: Option<Vec<PreopenConfig>>,
+    /// added to the variables allowed by `runtime.env`
+    envs: BTreeMap<String, String>,
+}
+
+impl TaskSettings {
+    fn from_metadata(metadata: &MetadataMap) -> Result<Self> {
+        Ok(Self {
+            limits: task_setting(metadata, "psh-task-limits-bin")?,
+            preopens: task_setting(metadata, "psh-task-preopens-bin")?,
+            envs: task_setting(metadata, "psh-task-envs-bin")?.unwrap_or_default(),
+        })
+    }
+}
+
+fn task_setting<T: DeserializeOwned>(metadata: &MetadataMap, key: &str) -> Result<Option<T>> {
+    let Some(value) = metadata.get_bin(key) else {
+        return Ok(None);
+    };
+    let bytes = value
+        .to_bytes()
+        .map_err(|_| anyhow!("Invalid base64 in {}", key))?;
+    let setting = serde_json::from_slice(&bytes).with_context(|| format!("Invalid {}", key))?;
+    Ok(Some(setting))
 }
 
 fn into_req<T>(message: T, token: &str) -> Result<Request<T>> {
@@ -39,36 +172,127 @@ fn into_req<T>(message: T, token: &str) -> Result<Request<T>> {
 }
 
 impl RpcClient {
-    pub async fn new(config: &RpcConfig, token: String) -> Result<Self> {
+    pub async fn new(
+        config: &RpcConfig,
+        token: String,
+        counters: Arc<ExportCounters>,
+    ) -> Result<Self> {
         let ep = Endpoint::from_shared(config.addr.clone())?
             .tls_config(ClientTlsConfig::new().with_native_roots())?;
-        let client: PshServiceClient<Channel> = PshServiceClient::connect(ep).await?;
-        Ok(Self { token, client })
+        let client = PshServiceClient::new(Metered(ep.connect().await?))
+            .accept_compressed(CompressionEncoding::Gzip)
+            .accept_compressed(CompressionEncoding::Zstd);
+        let compression = match config.compression {
+            Compression::None => None,
+            Compression::Gzip => Some((CompressionEncoding::Gzip, "gzip")),
+            Compression::Zstd => Some((CompressionEncoding::Zstd, "zstd")),
+        }
+        .map(|(encoding, name)| Compressed {
+            client: client.clone().send_compressed(encoding),
+            name,
+            accepted: Arc::new(AtomicBool::new(false)),
+        });
+        Ok(Self {
+            token,
+            client,
+            compression,
+            counters,
+        })
+    }
+
+    /// Call the server, compressing the request if it accepts the encoding.
+    async fn call<T, F>(&self, f: impl FnOnce(PshServiceClient<Metered>) -> F) -> Result<T>
+    where
+        F: Future<Output = Result<Response<T>, Status>>,
+    {
+        Ok(self.call_with_metadata(f).await?.into_inner())
     }
 
-    pub async fn send_host_info(&mut self, instance_id: String) -> Result<()> {
+    /// Like `call`, but the metadata of the response is kept.
+    async fn call_with_metadata<T, F>(
+        &self,
+        f: impl FnOnce(PshServiceClient<Metered>) -> F,
+    ) -> Result<Response<T>>
+    where
+        F: Future<Output = Result<Response<T>, Status>>,
+    {
+        let Some(compression) = &self.compression else {
+            return Ok(f(self.client.clone()).await?);
+        };
+
+        let res = if compression.accepted.load(Ordering::Relaxed) {
+            f(compression.client.clone()).await
+        } else {
+            f(self.client.clone()).await
+        };
+        // the server lists the encodings it accepts in each response
+        let accepted = match &res {
+            Ok(resp) => resp
+                .metadata()
+                .get("grpc-accept-encoding")
+                .and_then(|it| it.to_str().ok())
+                .is_some_and(|it| it.split(',').any(|it| it.trim() == compression.name)),
+            Err(status) if status.code() == Code::Unimplemented => false,
+            Err(_) => return Ok(res?),
+        };
+        if compression.accepted.swap(accepted, Ordering::Relaxed) != accepted {
+            tracing::info!(
+                "Server {} {} compression",
+                if accepted { "accepts" } else { "refuses" },
+                compression.name
+            );
+        }
+        Ok(res?)
+    }
+
+    pub async fn send_host_info(&self, instance_id: String) -> Result<()> {
         let req = into_req(new_info_req(instance_id), &self.token)?;
-        let resp = self.client.send_host_info(req).await?;
-        tracing::trace!("{:?}", resp.get_ref());
+        let resp = self
+            .call(|mut client| async move { client.send_host_info(req).await })
+            .await?;
+        tracing::trace!("{:?}", resp);
         Ok(())
     }
 
-    pub async fn export_data(&mut self, message: ExportDataReq) -> Result<()> {
-        let req = into_req(message, &self.token)?;
-        self.client.export_data(req).await?;
+    pub async fn export_data(&self, message: ExportDataReq) -> Result<()> {
+        let raw = message.encoded_len() as u64;
+        let sent = SentBytes::default();
+        let mut req = into_req(message, &self.token)?;
+        req.extensions_mut().insert(sent.clone());
+        self.call(|mut client| async move { client.export_data(req).await })
+            .await?;
+        self.counters.add(raw, sent.0.load(Ordering::Relaxed));
         Ok(())
     }
 
-    pub async fn heartbeat(&mut self, message: HeartbeatReq) -> Result<()> {
+    /// Returns the ids of the tasks the server cancels, see `doc/rpc.md`.
+    pub async fn heartbeat(&self, message: HeartbeatReq) -> Result<Vec<String>> {
         let req = into_req(message, &self.token)?;
-        self.client.heartbeat(req).await?;
-        Ok(())
+        let resp = self
+            .call_with_metadata(|mut client| async move { client.heartbeat(req).await })
+            .await?;
+        let cancelled = resp
+            .metadata()
+            .get_all(CANCEL_KEY)
+            .iter()
+            .filter_map(|it| it.to_str().ok())
+            .flat_map(|it| it.split(','))
+            .map(str::trim)
+            .filter(|it| !it.is_empty())
+            .map(str::to_owned)
+            .collect();
+        Ok(cancelled)
     }
 
-    pub async fn get_task(&mut self, instance_id: String) -> Result<Option<Task>> {
+    /// The next task for this instance, or the report rejecting it if its settings are invalid.
+    pub async fn get_task(&self, instance_id: String) -> Result<Option<Result<Task, TaskReport>>> {
         let req = into_req(GetTaskReq { instance_id }, &self.token)?;
 
-        let Some(task) = self.client.get_task(req).await?.into_inner().task else {
+        let resp = self
+            .call_with_metadata(|mut client| async move { client.get_task(req).await })
+            .await?;
+        let (metadata, resp, _) = resp.into_parts();
+        let Some(task) = resp.task else {
             return Ok(None);
         };
 
@@ -76,25 +300,63 @@ impl RpcClient {
             LocalResult::Single(t) => t,
             _ => bail!("Invalid task end time"),
         };
+        let settings = match TaskSettings::from_metadata(&metadata) {
+            Ok(it) => it,
+            Err(e) => {
+                let error = Some(format!("{:#}", e));
+                let report =
+                    TaskReport::aborted(Some(task.id.clone()), task.id, TaskState::Rejected, error);
+                return Ok(Some(Err(report)));
+            }
+        };
         let task = Task {
             id: Some(task.id),
             wasm_component: task.wasm,
+            // only embedded signatures until psh-proto carries detached ones
+            signature: None,
             wasm_component_args: task.wasm_args,
             end_time,
+            envs: settings.envs.into_iter().collect(),
+            limits: settings.limits,
+            preopens: settings.preopens,
+            allow_precompiled: false,
+            capture_output: None,
         };
 
-        Ok(Some(task))
+        Ok(Some(Ok(task)))
     }
 
-    pub async fn task_done(&mut self, task_id: String) -> Result<()> {
+    pub async fn task_done(&self, task_id: String) -> Result<()> {
         let req = into_req(TaskDoneReq { task_id }, &self.token)?;
-        self.client.task_done(req).await?;
+        self.call(|mut client| async move { client.task_done(req).await })
+            .await?;
         Ok(())
     }
 
-    pub async fn new_instance_id(&mut self) -> Result<String> {
+    /// Send the outcome of a task as a `psh_task` record, kept in `spool` if it can't be sent,
+    /// which must be replayed to the server.
+    pub async fn send_report(&self, instance_id: &str, report: &TaskReport, spool: Option<&Spool>) {
+        let req = ExportDataReq {
+            task_id: report.key.clone(),
+            data: vec![report.to_data(instance_id)],
+        };
+        let Err(e) = self.export_data(req.clone()).await else {
+            return;
+        };
+        match spool {
+            Some(spool) => {
+                tracing::warn!("Spooled the report of task {}: {}", report.key, e);
+                spool.store(&req);
+            }
+            None => tracing::error!("Failed to send the report of task {}: {}", report.key, e),
+        }
+    }
+
+    pub async fn new_instance_id(&self) -> Result<String> {
         let req = into_req(Unit {}, &self.token)?;
-        let resp = self.client.new_instance_id(req).await?;
-        Ok(resp.into_inner().instance_id)
+        let resp = self
+            .call(|mut client| async move { client.new_instance_id(req).await })
+            .await?;
+        Ok(resp.instance_id)
     }
 }
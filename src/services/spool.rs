@@ -0,0 +1,461 @@
+// Copyright (c) 2023-2024 Optimatist Technology Co., Ltd. All rights reserved.
+// DO NOT ALTER OR REMOVE COPYRIGHT NOTICES OR THIS FILE HEADER.
+//
+// This file is part of PSH.
+//
+// PSH is free software: you can redistribute it and/or modify it under the terms of the GNU Lesser General Public License
+// as published by the Free Software Foundation, either version 3 of the License, or (at your option) any later version.
+//
+// PSH is distributed in the hope that it will be useful, but WITHOUT ANY WARRANTY; without even
+// the implied warranty of MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the GNU Lesser General Public License for more details.
+//
+// You should have received a copy of the GNU Lesser General Public License along with Performance Savior Home (PSH). If not,
+// see <https://www.gnu.org/licenses/>.
+
+//! Write-ahead spool of `ExportDataReq` the remote sink could not take.
+//!
+//! Requests are appended to segment files `<seq>.seg`, each one prefixed by its
+//! encoded length as a little endian u32. Segments are replayed oldest first and
+//! removed once all their requests are sent, a segment partly replayed before a
+//! restart is sent again from its start. A request the server refuses for good
+//! is appended to `<seq>.rejected` in the same format and kept aside.
+
+use std::collections::VecDeque;
+use std::fs::{self, DirBuilder, File, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Result;
+use prost::Message;
+use serde::Serialize;
+use tokio::sync::{watch, Notify};
+
+use crate::config::SpoolConfig;
+use crate::runtime::Sink;
+use psh_proto::ExportDataReq;
+use tonic::Code;
+
+const EXTENSION: &str = "seg";
+const REJECTED: &str = "rejected";
+
+pub struct Spool {
+    dir: PathBuf,
+    max_size: u64,
+    segment_size: u64,
+    backoff_min: Duration,
+    backoff_max: Duration,
+    segments: Mutex<Segments>,
+    notify: Notify,
+    spooled: AtomicU64,
+    replayed: AtomicU64,
+    dropped: AtomicU64,
+}
+
+#[derive(Default)]
+struct Segments {
+    /// `(seq, size)` of the segments waiting for replay, oldest first
+    closed: VecDeque<(u64, u64)>,
+    /// the segment being appended to
+    active: Option<(u64, File, u64)>,
+    /// the segment being replayed
+    replaying: Option<(u64, u64)>,
+    next_seq: u64,
+    /// in bytes, of all segments
+    size: u64,
+}
+
+impl Segments {
+    fn is_empty(&self) -> bool {
+        self.closed.is_empty() && self.active.is_none() && self.replaying.is_none()
+    }
+
+    fn close_active(&mut self) {
+        if let Some((seq, _, size)) = self.active.take() {
+            self.closed.push_back((seq, size));
+        }
+    }
+}
+
+/// Counters of the spool, in bytes.
+#[derive(Serialize)]
+pub struct SpoolStats {
+    /// waiting for replay
+    pub pending: u64,
+    pub spooled: u64,
+    pub replayed: u64,
+    pub dropped: u64,
+}
+
+impl Spool {
+    /// Open the spool, segments left by a previous run are replayed first.
+    pub fn open(cfg: &SpoolConfig) -> Result<Self> {
+        let dir = PathBuf::from(&cfg.dir);
+        DirBuilder::new().recursive(true).mode(0o700).create(&dir)?;
+
+        let mut closed = vec![];
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|it| it != EXTENSION) {
+                continue;
+            }
+            let Some(seq) = path
+                .file_stem()
+                .and_then(|it| it.to_str())
+                .and_then(|it| it.parse::<u64>().ok())
+            else {
+                continue;
+            };
+            closed.push((seq, fs::metadata(&path)?.len()));
+        }
+        closed.sort_unstable();
+
+        let size = closed.iter().map(|(_, size)| size).sum();
+        if size > 0 {
+            tracing::info!("{} bytes of spooled data to replay", size);
+        }
+        let segments = Segments {
+            next_seq: closed.last().map_or(0, |(seq, _)| seq + 1),
+            closed: closed.into(),
+            active: None,
+            replaying: None,
+            size,
+        };
+
+        Ok(Self {
+            dir,
+            max_size: cfg.max_size,
+            segment_size: cfg.segment_size,
+            backoff_min: Duration::from_secs(cfg.backoff_min),
+            backoff_max: Duration::from_secs(cfg.backoff_max),
+            segments: Mutex::new(segments),
+            notify: Notify::new(),
+            spooled: AtomicU64::new(0),
+            replayed: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+        })
+    }
+
+    fn path(&self, seq: u64) -> PathBuf {
+        self.dir.join(format!("{:020}.{}", seq, EXTENSION))
+    }
+
+    /// Nothing is waiting for replay, new requests can be sent directly.
+    pub fn is_empty(&self) -> bool {
+        self.segments.lock().unwrap().is_empty()
+    }
+
+    pub fn stats(&self) -> SpoolStats {
+        SpoolStats {
+            pending: self.segments.lock().unwrap().size,
+            spooled: self.spooled.load(Ordering::Relaxed),
+            replayed: self.replayed.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Keep a request for replay, it is dropped if it can't be written.
+    pub fn store(&self, req: &ExportDataReq) {
+        let len = record_len(req);
+        match self.append(req) {
+            Ok(()) => {
+                self.spooled.fetch_add(len, Ordering::Relaxed);
+                self.notify.notify_one();
+            }
+            Err(e) => {
+                self.dropped.fetch_add(len, Ordering::Relaxed);
+                tracing::error!("Failed to spool data of task {}: {}", req.task_id, e);
+            }
+        }
+    }
+
+    fn append(&self, req: &ExportDataReq) -> Result<()> {
+        let record = encode_record(req);
+        let len = record.len() as u64;
+
+        let mut segments = self.segments.lock().unwrap();
+        if segments
+            .active
+            .as_ref()
+            .is_some_and(|(_, _, size)| size + len > self.segment_size)
+        {
+            segments.close_active();
+        }
+        if segments.active.is_none() {
+            let seq = segments.next_seq;
+            let file = OpenOptions::new()
+                .append(true)
+                .create_new(true)
+                .open(self.path(seq))?;
+            segments.next_seq += 1;
+            segments.active = Some((seq, file, 0));
+        }
+
+        let (_, file, size) = segments.active.as_mut().expect("active segment");
+        file.write_all(&record)?;
+        file.sync_data()?;
+        *size += len;
+        segments.size += len;
+
+        // drop the oldest segments, but not the one being replayed or appended to
+        while segments.size > self.max_size {
+            let Some((seq, size)) = segments.closed.pop_front() else {
+                break;
+            };
+            self.remove(seq);
+            segments.size -= size;
+            self.dropped.fetch_add(size, Ordering::Relaxed);
+            tracing::warn!("Spool is full, dropped {} bytes of the oldest data", size);
+        }
+        drop(segments);
+        Ok(())
+    }
+
+    fn remove(&self, seq: u64) {
+        if let Err(e) = fs::remove_file(self.path(seq)) {
+            tracing::warn!("Failed to remove spool segment {}: {}", seq, e);
+        }
+    }
+
+    /// Take the oldest segment for replay, closing the active one if there is no other.
+    fn next_segment(&self) -> Option<u64> {
+        let mut segments = self.segments.lock().unwrap();
+        if segments.closed.is_empty() {
+            segments.close_active();
+        }
+        let (seq, size) = segments.closed.pop_front()?;
+        segments.replaying = Some((seq, size));
+        drop(segments);
+        Some(seq)
+    }
+
+    fn finish_segment(&self) {
+        let mut segments = self.segments.lock().unwrap();
+        if let Some((seq, size)) = segments.replaying.take() {
+            self.remove(seq);
+            segments.size -= size;
+        }
+    }
+
+    /// Set a request of segment `seq` aside, it would never be accepted.
+    fn reject(&self, seq: u64, req: &ExportDataReq) {
+        self.dropped.fetch_add(record_len(req), Ordering::Relaxed);
+        let path = self.path(seq).with_extension(REJECTED);
+        let res = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&path)
+            .and_then(|mut file| file.write_all(&encode_record(req)));
+        if let Err(e) = res {
+            tracing::warn!(
+                "Failed to set rejected data aside in {}: {}",
+                path.display(),
+                e
+            );
+        }
+    }
+
+    /// Send the spooled requests in order until shutdown, backing off while the backend is unreachable.
+    pub async fn replay(self: Arc<Self>, sink: Sink, mut shutdown: watch::Receiver<bool>) {
+        let mut backoff = self.backoff_min;
+        loop {
+            let Some(seq) = self.next_segment() else {
+                tokio::select! {
+                    _ = self.notify.notified() => continue,
+                    _ = shutdown.wait_for(|it| *it) => return,
+                }
+            };
+
+            let mut reqs = read_segment(&self.path(seq)).into_iter().peekable();
+            while let Some(req) = reqs.peek() {
+                let len = record_len(req);
+                match sink.send(req).await {
+                    Ok(()) => {
+                        self.replayed.fetch_add(len, Ordering::Relaxed);
+                        backoff = self.backoff_min;
+                        reqs.next();
+                    }
+                    Err(e) if is_permanent(&e) => {
+                        tracing::error!(
+                            "Server refused spooled data of task {}, set it aside in {}: {}",
+                            req.task_id,
+                            self.path(seq).with_extension(REJECTED).display(),
+                            e
+                        );
+                        self.reject(seq, req);
+                        reqs.next();
+                    }
+                    Err(e) => {
+                        tracing::warn!(
+                            "Failed to replay spooled data, retrying in {}s: {}",
+                            backoff.as_secs(),
+                            e
+                        );
+                        tokio::select! {
+                            _ = tokio::time::sleep(backoff) => {}
+                            // the segment is kept and replayed again after a restart
+                            _ = shutdown.wait_for(|it| *it) => return,
+                        }
+                        backoff = (backoff * 2).min(self.backoff_max);
+                    }
+                }
+            }
+            self.finish_segment();
+        }
+    }
+}
+
+/// The server refuses the request for good, retrying it won't help.
+/// Auth errors are retried, they come from the config rather than the request.
+fn is_permanent(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<tonic::Status>()
+        .is_some_and(|it| it.code() == Code::InvalidArgument)
+}
+
+/// A request prefixed by its encoded length.
+fn encode_record(req: &ExportDataReq) -> Vec<u8> {
+    let encoded = req.encode_to_vec();
+    let mut record = Vec::with_capacity(4 + encoded.len());
+    record.extend_from_slice(&(encoded.len() as u32).to_le_bytes());
+    record.extend_from_slice(&encoded);
+    record
+}
+
+/// Size of a request in a segment.
+fn record_len(req: &ExportDataReq) -> u64 {
+    4 + req.encoded_len() as u64
+}
+
+/// Requests of a segment, a record truncated by a crash ends it.
+fn read_segment(path: &Path) -> Vec<ExportDataReq> {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            tracing::error!("Failed to read spool segment {}: {}", path.display(), e);
+            return vec![];
+        }
+    };
+
+    let mut reqs = vec![];
+    let mut rest = bytes.as_slice();
+    while !rest.is_empty() {
+        let Some((len, tail)) = rest.split_first_chunk::<4>() else {
+            break;
+        };
+        let len = u32::from_le_bytes(*len) as usize;
+        if tail.len() < len {
+            break;
+        }
+        let (record, tail) = tail.split_at(len);
+        match ExportDataReq::decode(record) {
+            Ok(req) => reqs.push(req),
+            Err(e) => tracing::warn!("Skipped a corrupted record in {}: {}", path.display(), e),
+        }
+        rest = tail;
+    }
+    if !rest.is_empty() {
+        tracing::warn!("Skipped a truncated record in {}", path.display());
+    }
+    reqs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TempDir;
+
+    fn config(dir: &TempDir, segment_size: u64) -> SpoolConfig {
+        SpoolConfig {
+            enable: true,
+            dir: dir.path().to_string_lossy().into_owned(),
+            max_size: 1 << 20,
+            segment_size,
+            backoff_min: 1,
+            backoff_max: 1,
+        }
+    }
+
+    fn req(n: usize) -> ExportDataReq {
+        ExportDataReq {
+            task_id: format!("t{n}"),
+            ..Default::default()
+        }
+    }
+
+    /// Task ids of the spooled requests, in replay order.
+    fn drain(spool: &Spool) -> Vec<Vec<String>> {
+        let mut segments = vec![];
+        while let Some(seq) = spool.next_segment() {
+            let reqs = read_segment(&spool.path(seq));
+            segments.push(reqs.into_iter().map(|it| it.task_id).collect());
+            spool.finish_segment();
+        }
+        segments
+    }
+
+    #[test]
+    fn test_replay_order() {
+        let dir = TempDir::new("spool-order");
+        let cfg = config(&dir, 1 << 20);
+        let spool = Spool::open(&cfg).unwrap();
+        assert!(spool.is_empty());
+        for n in 0..5 {
+            spool.store(&req(n));
+        }
+        assert!(!spool.is_empty());
+        assert_eq!(drain(&spool), [["t0", "t1", "t2", "t3", "t4"]]);
+        assert!(spool.is_empty());
+        assert_eq!(spool.stats().pending, 0);
+        assert_eq!(fs::read_dir(&cfg.dir).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_segment_rollover() {
+        let dir = TempDir::new("spool-rollover");
+        let cfg = config(&dir, 2 * record_len(&req(0)));
+        let spool = Spool::open(&cfg).unwrap();
+        for n in 0..5 {
+            spool.store(&req(n));
+        }
+        drop(spool);
+
+        // segments left by a previous run are replayed oldest first
+        let spool = Spool::open(&cfg).unwrap();
+        assert_eq!(spool.stats().pending, 5 * record_len(&req(0)));
+        assert_eq!(
+            drain(&spool),
+            [vec!["t0", "t1"], vec!["t2", "t3"], vec!["t4"]]
+        );
+    }
+
+    #[test]
+    fn test_reject_one_request() {
+        let dir = TempDir::new("spool-reject");
+        let cfg = config(&dir, 1 << 20);
+        let spool = Spool::open(&cfg).unwrap();
+        for n in 0..3 {
+            spool.store(&req(n));
+        }
+        let seq = spool.next_segment().unwrap();
+        spool.reject(seq, &req(1));
+        spool.finish_segment();
+
+        // only the refused request is set aside
+        let rejected = read_segment(&spool.path(seq).with_extension(REJECTED));
+        assert_eq!(rejected, [req(1)]);
+        assert_eq!(spool.stats().dropped, record_len(&req(1)));
+    }
+
+    #[test]
+    fn test_is_permanent() {
+        let status = |code| anyhow::Error::from(tonic::Status::new(code, ""));
+        assert!(is_permanent(&status(Code::InvalidArgument)));
+        assert!(!is_permanent(&status(Code::Unauthenticated)));
+        assert!(!is_permanent(&status(Code::PermissionDenied)));
+        assert!(!is_permanent(&status(Code::Unavailable)));
+        assert!(!is_permanent(&anyhow::anyhow!("connection refused")));
+    }
+}